@@ -1,178 +1,810 @@
-use std::error::Error;
+use std::{
+    any::Any,
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use log::debug;
+use futures::FutureExt;
+use log::{debug, error, info, warn};
+use rand::Rng;
 use regex::Regex;
-use reqwest::Response;
-use serde::{Deserialize, Serialize};
-use tokio::sync::{mpsc::Receiver, Mutex};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{oneshot, Semaphore};
 
-use crate::models::{CQEvent, Plugin, PluginSenario};
+use crate::{
+    breaker::{CircuitBreaker, CircuitBreakerConfig},
+    error::{BotError, BotResult},
+    heartbeat::{HeartbeatMonitor, StatusChange},
+    help::{self, HelpConfig, HelpDelivery, HelpQuery},
+    i18n::{Catalog, I18nConfig, Translator},
+    members::{GroupMembers, Member, MemberCache},
+    models::{
+        CQEvent, HandleOutcome, Middleware, MiddlewareFlow, Plugin, PluginFlow, PluginSenario,
+    },
+    plugins::BoxedPlugin,
+    queue::EventQueue,
+    session::{SessionManager, SessionOptions, SessionReply},
+};
+
+tokio::task_local! {
+    // self_id of the account whose event is being dispatched
+    static CURRENT_SELF_ID: i64;
+    // messages sent by the plugin currently handling an event
+    static SENT_MESSAGES: Cell<usize>;
+    // set when a middleware muted the plugin currently handling an event
+    static MUTED: bool;
+    // dropped to let the next event of the same chat be dispatched
+    static CHAT_TURN: Cell<Option<oneshot::Sender<()>>>;
+}
+
+// (self_id, group_id, user_id), user_id is None for group chats and group_id
+// for private ones
+type ChatKey = (i64, Option<i64>, Option<i64>);
 
 #[derive(Deserialize, Serialize)]
 pub struct BotConfig {
     pub listen_addr: String,
+    // used for events from accounts that are not listed in `accounts`
     pub cq_addr: String,
+    pub access_token: Option<String>,
+    #[serde(default)]
+    pub accounts: Vec<AccountConfig>,
+    // tell the user when a plugin fails, with an id to look up in the log
+    #[serde(default)]
+    pub report_errors: bool,
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    // a plugin is disabled after panicking this many times, 0 keeps it forever
+    #[serde(default = "default_max_plugin_panics")]
+    pub max_plugin_panics: usize,
+    // heartbeats that may be missed before an account counts as disconnected
+    #[serde(default = "default_heartbeat_misses")]
+    pub heartbeat_misses: u32,
+    // admins in every group, and the users told about disconnects
+    #[serde(default)]
+    pub superusers: Vec<i64>,
+    // DM the superusers when an account stops sending heartbeats and when it
+    // comes back
+    #[serde(default)]
+    pub notify_superusers: bool,
+    // other bot accounts whose messages plugins do not see by default
+    #[serde(default)]
+    pub known_bots: Vec<i64>,
+    // seconds a fetched group member list is reused for display names
+    #[serde(default = "default_member_cache_ttl")]
+    pub member_cache_ttl: u64,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub help: HelpConfig,
+    #[serde(default)]
+    pub i18n: I18nConfig,
+}
+
+fn default_max_plugin_panics() -> usize {
+    3
+}
+
+fn default_queue_size() -> usize {
+    100
+}
+
+fn default_heartbeat_misses() -> u32 {
+    3
+}
+
+fn default_member_cache_ttl() -> u64 {
+    600
 }
 impl Default for BotConfig {
     fn default() -> Self {
         BotConfig {
             listen_addr: "127.0.0.1:5701".to_string(),
             cq_addr: "127.0.0.1:5700".to_string(),
+            access_token: None,
+            accounts: Vec::new(),
+            report_errors: false,
+            max_plugin_panics: default_max_plugin_panics(),
+            queue_size: default_queue_size(),
+            heartbeat_misses: default_heartbeat_misses(),
+            superusers: Vec::new(),
+            notify_superusers: false,
+            known_bots: Vec::new(),
+            member_cache_ttl: default_member_cache_ttl(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            help: HelpConfig::default(),
+            i18n: I18nConfig::default(),
         }
     }
 }
+
+#[derive(Deserialize, Serialize)]
+pub struct AccountConfig {
+    pub self_id: i64,
+    // `host:port` defaults to plain http, a full `https://...` url is used as is
+    pub cq_addr: String,
+    pub access_token: Option<String>,
+    // names of the plugins enabled for this account, all when absent
+    pub plugins: Option<Vec<String>>,
+}
 pub struct Bot {
-    plugins: Vec<Box<dyn Plugin + Send + Sync>>,
+    plugins: Vec<BoxedPlugin>,
+    middlewares: Vec<Box<dyn Middleware + Send + Sync>>,
     config: BotConfig,
-    event_receiver: Mutex<Receiver<CQEvent>>,
+    queue: Arc<EventQueue>,
     client: reqwest::Client,
+    sessions: SessionManager,
+    panic_counts: Mutex<HashMap<&'static str, usize>>,
+    disabled_plugins: Mutex<HashSet<&'static str>>,
+    heartbeats: HeartbeatMonitor,
+    breaker: CircuitBreaker,
+    catalog: Catalog,
+    members: MemberCache,
+    // one permit per event popped from the queue and not dispatched yet
+    in_flight: Arc<Semaphore>,
+    // resolves when the last event dispatched for the chat is done, tagged
+    // with that dispatch so it only removes its own entry
+    chat_turns: Mutex<HashMap<ChatKey, (u64, oneshot::Receiver<()>)>>,
 }
 
 impl Bot {
-    pub fn new(rx: Receiver<CQEvent>, cfg: BotConfig) -> Self {
+    pub fn new(queue: Arc<EventQueue>, mut cfg: BotConfig) -> Self {
         Bot {
             plugins: Vec::new(),
-            config: cfg,
-            event_receiver: Mutex::new(rx),
+            middlewares: Vec::new(),
+            queue,
             client: reqwest::Client::new(),
+            sessions: SessionManager::default(),
+            panic_counts: Mutex::new(HashMap::new()),
+            disabled_plugins: Mutex::new(HashSet::new()),
+            heartbeats: HeartbeatMonitor::new(cfg.heartbeat_misses),
+            breaker: CircuitBreaker::new(cfg.circuit_breaker.clone()),
+            catalog: Catalog::new(std::mem::take(&mut cfg.i18n)),
+            members: MemberCache::new(Duration::from_secs(cfg.member_cache_ttl)),
+            in_flight: Arc::new(Semaphore::new(cfg.queue_size.max(1))),
+            chat_turns: Mutex::new(HashMap::new()),
+            config: cfg,
         }
     }
-    pub fn register_plugin(&mut self, plugin: impl Plugin + Send + Sync + 'static) {
-        self.plugins.push(Box::new(plugin));
+    // plugins see events in registration order
+    pub fn register_plugin(&mut self, plugin: BoxedPlugin) {
+        self.plugins.push(plugin);
+    }
+    // middlewares run in registration order before each plugin and after it
+    pub fn register_middleware(&mut self, middleware: impl Middleware + Send + Sync + 'static) {
+        self.middlewares.push(Box::new(middleware));
     }
-    pub async fn run(&self) {
+    pub async fn run(self: Arc<Self>) {
+        tokio::spawn(self.clone().watch_heartbeats());
+        let mut turn_id: u64 = 0;
         loop {
-            let event = self.event_receiver.lock().await.recv().await.unwrap();
-            self.handle_help(event.clone()).await.ok();
-            for plugin in &self.plugins {
-                let self_cln = self;
-                let evt_cln = event.clone();
-                // tokio::spawn(async move {
-                match plugin.handle(evt_cln.clone(), self_cln).await {
-                    Ok(_) => (),
-                    Err(err) => debug!(
-                        "an error occurred: {:?}\nwhen plugin {} is handling event: {:?}",
-                        err,
-                        plugin.name(),
-                        evt_cln
-                    ),
+            // while `queue_size` events are being dispatched the queue fills
+            // up and sheds or rejects new events instead
+            let permit = self
+                .in_flight
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the in-flight semaphore is never closed");
+            let event = self.queue.pop().await;
+            self.members.observe(&event);
+            // events of one chat are dispatched in order, different chats
+            // and sessions waiting for the next message run concurrently
+            turn_id += 1;
+            let (turn, next_turn) = oneshot::channel();
+            let chat_key = Self::chat_key(&event);
+            let previous_turn = chat_key.and_then(|key| {
+                self.chat_turns
+                    .lock()
+                    .unwrap()
+                    .insert(key, (turn_id, next_turn))
+            });
+            let bot = self.clone();
+            tokio::spawn(async move {
+                if let Some((_, previous_turn)) = previous_turn {
+                    previous_turn.await.ok();
+                }
+                // messages a plugin is waiting for skip the normal dispatch,
+                // the previous turn had the chance to start waiting for them
+                let event = if bot.is_from_bot(&event) {
+                    Some(event)
+                } else {
+                    bot.sessions.feed(event).await
+                };
+                if let Some(event) = event {
+                    let dispatch = CURRENT_SELF_ID.scope(event.self_id, bot.dispatch(event));
+                    CHAT_TURN.scope(Cell::new(Some(turn)), dispatch).await;
+                }
+                // the chat is idle again unless a later event took the turn
+                if let Some(key) = chat_key {
+                    let mut chat_turns = bot.chat_turns.lock().unwrap();
+                    if chat_turns.get(&key).is_some_and(|(id, _)| *id == turn_id) {
+                        chat_turns.remove(&key);
+                    }
+                }
+                drop(permit);
+            });
+        }
+    }
+    /// Tracks heartbeats and lifecycle events as they arrive, they never wait
+    /// in the queue behind a busy bot.
+    pub fn observe_meta(self: &Arc<Self>, event: &CQEvent) {
+        if let Some(change) = self.heartbeats.observe(event) {
+            tokio::spawn(self.clone().on_status_change(change));
+        }
+    }
+    fn chat_key(event: &CQEvent) -> Option<ChatKey> {
+        match (event.group_id, event.user_id) {
+            (Some(group_id), _) => Some((event.self_id, Some(group_id), None)),
+            (None, Some(user_id)) => Some((event.self_id, None, Some(user_id))),
+            (None, None) => None,
+        }
+    }
+    async fn watch_heartbeats(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            for change in self.heartbeats.expired() {
+                tokio::spawn(self.clone().on_status_change(change));
+            }
+        }
+    }
+    async fn on_status_change(self: Arc<Self>, change: StatusChange) {
+        let tr = self.catalog.translator(self.catalog.default_locale());
+        let (self_id, notice) = match change {
+            StatusChange::Connected(self_id) => {
+                info!("account {self_id} connected");
+                (self_id, None)
+            }
+            StatusChange::Reconnected(self_id) => {
+                info!("account {self_id} reconnected");
+                let notice = tr.t_args("bot.reconnected", &[("self_id", &self_id)]);
+                (self_id, Some(notice))
+            }
+            StatusChange::Disconnected(self_id) => {
+                warn!("account {self_id} stopped sending heartbeats");
+                let notice = tr.t_args("bot.heartbeat_lost", &[("self_id", &self_id)]);
+                (self_id, Some(notice))
+            }
+        };
+        for plugin in &self.plugins {
+            if !self.is_plugin_enabled(self_id, plugin.name()) {
+                continue;
+            }
+            let result = match change {
+                StatusChange::Connected(_) | StatusChange::Reconnected(_) => {
+                    plugin.on_connect(self_id, &self).await
+                }
+                StatusChange::Disconnected(_) => plugin.on_disconnect(self_id, &self).await,
+            };
+            if let Err(err) = result {
+                warn!(
+                    "plugin {} failed to handle status change: {}",
+                    plugin.name(),
+                    err
+                );
+            }
+        }
+        let notice = match (notice, self.config.notify_superusers) {
+            (Some(notice), true) => notice,
+            _ => return,
+        };
+        for user_id in &self.config.superusers {
+            // sent through the default endpoint, the account itself may be down
+            self.api_request_as::<serde_json::Value>(
+                None,
+                "send_private_msg",
+                json!({ "user_id": user_id, "message": notice }),
+            )
+            .await
+            .map_err(|err| warn!("failed to notify superuser {user_id}: {err}"))
+            .ok();
+        }
+    }
+    /// Translator for the locale of the chat `event` came from.
+    pub fn tr(&self, event: &CQEvent) -> Translator<'_> {
+        self.catalog.translator(self.catalog.locale_of(event))
+    }
+    /// Waits for the next message of the sender of `event` in the same chat.
+    pub async fn wait_next_message(
+        &self,
+        event: &CQEvent,
+        options: SessionOptions,
+    ) -> SessionReply {
+        let pending = self.sessions.register(event).await;
+        // the rest of the chat goes on while the plugin waits, the session is
+        // registered first so the next message of the chat reaches it
+        CHAT_TURN.try_with(|turn| drop(turn.take())).ok();
+        self.sessions.receive(pending, options).await
+    }
+    /// Members of `group_id`, fetched once and reused until they expire or
+    /// the group changes.
+    pub async fn group_members(&self, group_id: i64) -> BotResult<GroupMembers> {
+        // 0 stands for the default endpoint, outside of any event
+        let self_id = CURRENT_SELF_ID.try_with(|self_id| *self_id).unwrap_or(0);
+        if let Some(members) = self.members.get(self_id, group_id) {
+            return Ok(members);
+        }
+        let members: Vec<Member> = self
+            .api_request("get_group_member_list", json!({ "group_id": group_id }))
+            .await?;
+        Ok(self.members.insert(self_id, group_id, members))
+    }
+    /// Display name of `user_id` in `group_id`, the id itself when they are
+    /// not a member.
+    pub async fn member_name(&self, group_id: i64, user_id: i64) -> BotResult<String> {
+        Ok(self
+            .group_members(group_id)
+            .await?
+            .get(&user_id)
+            .map(|member| member.display_name().to_string())
+            .unwrap_or_else(|| user_id.to_string()))
+    }
+    pub fn superusers(&self) -> &[i64] {
+        &self.config.superusers
+    }
+    /// Whether the sender of `event` is a superuser or an owner or admin of
+    /// the group it was sent in.
+    pub fn is_admin(&self, event: &CQEvent) -> bool {
+        if event
+            .user_id
+            .is_some_and(|user_id| self.config.superusers.contains(&user_id))
+        {
+            return true;
+        }
+        let role = event
+            .sender
+            .as_ref()
+            .and_then(|sender| sender.role.as_deref());
+        matches!(role, Some("owner") | Some("admin"))
+    }
+    /// Whether `event` was sent by this bot or one of the `known_bots`,
+    /// including the echoes go-cqhttp reports as `message_sent`.
+    pub fn is_from_bot(&self, event: &CQEvent) -> bool {
+        if event.post_type == "message_sent" {
+            return true;
+        }
+        match event.user_id {
+            Some(user_id) => {
+                user_id == event.self_id
+                    || self.config.known_bots.contains(&user_id)
+                    || self
+                        .config
+                        .accounts
+                        .iter()
+                        .any(|acc| acc.self_id == user_id)
+            }
+            None => false,
+        }
+    }
+    async fn dispatch(&self, event: CQEvent) {
+        let from_bot = self.is_from_bot(&event);
+        if !from_bot {
+            // errors can only come from an actual help command
+            if !matches!(self.handle_help(event.clone()).await, Ok(PluginFlow::Pass)) {
+                return;
+            }
+        }
+        for plugin in &self.plugins {
+            if !self.is_plugin_enabled(event.self_id, plugin.name()) {
+                continue;
+            }
+            if from_bot && !plugin.accepts_bot_messages() {
+                continue;
+            }
+            if let PluginFlow::Consumed = self.handle_plugin(plugin.as_ref(), event.clone()).await {
+                debug!("plugin {} consumed the event", plugin.name());
+                break;
+            }
+        }
+    }
+    // a failed plugin still consumes the event, unless it panicked
+    async fn handle_plugin(
+        &self,
+        plugin: &(dyn Plugin + Send + Sync),
+        mut event: CQEvent,
+    ) -> PluginFlow {
+        let mut muted = false;
+        for middleware in &self.middlewares {
+            match middleware.before(&mut event, plugin, self).await {
+                MiddlewareFlow::Continue => {}
+                MiddlewareFlow::Skip => {
+                    debug!(
+                        "middleware {} skipped plugin {}",
+                        middleware.name(),
+                        plugin.name()
+                    );
+                    return PluginFlow::Pass;
+                }
+                MiddlewareFlow::Mute => {
+                    debug!(
+                        "middleware {} muted plugin {}",
+                        middleware.name(),
+                        plugin.name()
+                    );
+                    muted = true;
+                }
+            }
+        }
+        let started_at = Instant::now();
+        let (result, sent) = SENT_MESSAGES
+            .scope(Cell::new(0), async {
+                let result = MUTED
+                    .scope(
+                        muted,
+                        AssertUnwindSafe(plugin.handle(event.clone(), self)).catch_unwind(),
+                    )
+                    .await;
+                (result, SENT_MESSAGES.with(Cell::get))
+            })
+            .await;
+        let (flow, error) = match result {
+            Ok(Ok(flow)) => (flow, None),
+            // the plugin tried to answer, which still counts as handling it
+            Ok(Err(BotError::Muted)) => (PluginFlow::Consumed, None),
+            Ok(Err(err)) => {
+                let msg = err.to_string();
+                self.report_error(plugin, &event, err).await;
+                (PluginFlow::Consumed, Some(msg))
+            }
+            Err(payload) => (
+                PluginFlow::Pass,
+                Some(self.handle_panic(plugin, &event, payload)),
+            ),
+        };
+        let outcome = HandleOutcome {
+            error,
+            sent,
+            elapsed: started_at.elapsed(),
+        };
+        for middleware in &self.middlewares {
+            middleware.after(&event, plugin, &outcome, self).await;
+        }
+        flow
+    }
+    fn handle_panic(
+        &self,
+        plugin: &(dyn Plugin + Send + Sync),
+        event: &CQEvent,
+        payload: Box<dyn Any + Send>,
+    ) -> String {
+        let msg = match payload.downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(msg) => msg.to_string(),
+                Err(_) => "unknown panic".to_string(),
+            },
+        };
+        let count = {
+            let mut panic_counts = self.panic_counts.lock().unwrap();
+            let count = panic_counts.entry(plugin.name()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        error!(
+            "plugin {} panicked ({} time(s)): {}\nwhen handling event: {:?}",
+            plugin.name(),
+            count,
+            msg,
+            event
+        );
+        let max_panics = self.config.max_plugin_panics;
+        if max_panics != 0 && count >= max_panics {
+            error!("plugin {} panicked too often, disabling it", plugin.name());
+            self.disabled_plugins.lock().unwrap().insert(plugin.name());
+        }
+        format!("panicked: {msg}")
+    }
+    async fn report_error(
+        &self,
+        plugin: &(dyn Plugin + Send + Sync),
+        event: &CQEvent,
+        err: BotError,
+    ) {
+        if let BotError::UserInput(msg) = err {
+            self.reply(event, msg).await.ok();
+            return;
+        }
+        let error_id = format!("{:06x}", rand::thread_rng().gen_range(0..0x1000000));
+        warn!(
+            "[{}] an error occurred: {}\nwhen plugin {} is handling event: {:?}",
+            error_id,
+            err,
+            plugin.name(),
+            event
+        );
+        if self.config.report_errors && event.post_type == "message" {
+            let msg = self
+                .tr(event)
+                .t_args("bot.error", &[("error_id", &error_id)]);
+            self.reply(event, msg).await.ok();
+        }
+    }
+    fn account(&self, self_id: i64) -> Option<&AccountConfig> {
+        self.config
+            .accounts
+            .iter()
+            .find(|account| account.self_id == self_id)
+    }
+    fn is_plugin_enabled(&self, self_id: i64, name: &str) -> bool {
+        if self.disabled_plugins.lock().unwrap().contains(name) {
+            return false;
+        }
+        match self.account(self_id).and_then(|acc| acc.plugins.as_ref()) {
+            Some(plugins) => plugins.iter().any(|plugin| plugin == name),
+            None => true,
+        }
+    }
+    /// Calls `api` on the account that received the event being handled and
+    /// decodes the `data` field of the response. Messages sent into a group
+    /// pass the circuit breaker first.
+    pub async fn api_request<T: DeserializeOwned>(
+        &self,
+        api: &str,
+        json: impl Serialize,
+    ) -> BotResult<T> {
+        let self_id = CURRENT_SELF_ID.try_with(|self_id| *self_id).ok();
+        let json = serde_json::to_value(json)?;
+        if api.starts_with("send_") {
+            if MUTED.try_with(|muted| *muted).unwrap_or(false) {
+                return Err(BotError::Muted);
+            }
+            if let Some(group_id) = Self::target_group(api, &json) {
+                if !self.breaker.allow(group_id) {
+                    return Err(BotError::CircuitOpen(group_id));
                 }
-                // });
             }
+            SENT_MESSAGES.try_with(|sent| sent.set(sent.get() + 1)).ok();
         }
+        self.api_request_as(self_id, api, json).await
     }
-    pub async fn api_request(
+    // the group a send_* api call posts into
+    fn target_group(api: &str, json: &serde_json::Value) -> Option<i64> {
+        let is_group = match api {
+            "send_group_msg" | "send_group_forward_msg" => true,
+            "send_msg" => json["message_type"].as_str() != Some("private"),
+            _ => false,
+        };
+        let group_id = &json["group_id"];
+        match is_group {
+            true => group_id
+                .as_i64()
+                .or_else(|| group_id.as_str().and_then(|id| id.parse().ok())),
+            false => None,
+        }
+    }
+    pub async fn api_request_as<T: DeserializeOwned>(
         &self,
+        self_id: Option<i64>,
         api: &str,
         json: impl Serialize,
-    ) -> Result<Response, Box<dyn Error + Send>> {
-        self.client
-            .post(format!(
-                "http://{cq_addr}/{api}",
-                cq_addr = self.config.cq_addr
-            ))
-            .json(&json)
+    ) -> BotResult<T> {
+        let (cq_addr, access_token) = match self_id.and_then(|id| self.account(id)) {
+            Some(account) => (&account.cq_addr, &account.access_token),
+            None => (&self.config.cq_addr, &self.config.access_token),
+        };
+        let url = if cq_addr.starts_with("http://") || cq_addr.starts_with("https://") {
+            format!("{}/{api}", cq_addr.trim_end_matches('/'))
+        } else {
+            format!("http://{cq_addr}/{api}")
+        };
+        let mut req = self.client.post(url).json(&json);
+        if let Some(token) = access_token {
+            req = req.bearer_auth(token);
+        }
+        let resp = req
             .send()
             .await
-            .map_err(|err| Box::new(err) as Box<dyn Error + Send>)
+            .map_err(BotError::transport)?
+            .json::<ApiResponse>()
+            .await
+            .map_err(BotError::transport)?;
+        if resp.retcode != 0 {
+            return Err(BotError::Api {
+                api: api.to_string(),
+                retcode: resp.retcode,
+                msg: resp.wording.or(resp.msg).unwrap_or_default(),
+            });
+        }
+        Ok(serde_json::from_value(resp.data)?)
     }
-    async fn handle_help(&self, event: CQEvent) -> Result<(), Box<dyn Error + Send>> {
+    async fn handle_help(&self, event: CQEvent) -> BotResult<PluginFlow> {
         if event.post_type != "message" {
-            return Ok(());
+            return Ok(PluginFlow::Pass);
         }
-        let msg = event.raw_message.as_ref().unwrap();
+        let msg = match event.raw_message.as_ref() {
+            Some(msg) => msg,
+            None => return Ok(PluginFlow::Pass),
+        };
         let re = Regex::new(r"^(?P<cmd>>help)($|\s+(?P<content>.*)$)").unwrap();
         if !re.is_match(msg) {
-            return Ok(());
-        }
-        let CQEvent {
-            group_id,
-            user_id,
-            message_type,
-            ..
-        } = event;
-        let message_type = match message_type.unwrap().as_str() {
-            "private" => PluginSenario::Private,
-            "group" => PluginSenario::Group,
-            _ => unreachable!(),
+            return Ok(PluginFlow::Pass);
+        }
+        let message_type = match event.message_type.as_deref() {
+            Some("private") => PluginSenario::Private,
+            Some("group") => PluginSenario::Group,
+            _ => return Ok(PluginFlow::Pass),
         };
-        let mut resp = String::new();
-        let content = re.replace_all(&msg, "$content").to_string();
-        match content.as_str() {
-            "" => {
-                for plugin in self.plugins.iter() {
-                    if plugin.senario() == message_type || plugin.senario() == PluginSenario::Both {
-                        resp.push_str(
-                            format!("{:10}\t{}\r\n", plugin.name(), plugin.description()).as_str(),
-                        );
-                    }
-                }
-                if resp.is_empty() {
-                    resp.push_str("没有可用的插件");
-                }
+        let plugins: Vec<&(dyn Plugin + Send + Sync)> = self
+            .plugins
+            .iter()
+            .filter(|plugin| self.is_plugin_enabled(event.self_id, plugin.name()))
+            .filter(|plugin| {
+                plugin.senario() == message_type || plugin.senario() == PluginSenario::Both
+            })
+            .map(|plugin| plugin.as_ref())
+            .collect();
+        let content = re.replace_all(msg, "$content").to_string();
+        let help_config = &self.config.help;
+        let tr = self.tr(&event);
+        let resp = match HelpQuery::parse(&content) {
+            HelpQuery::Page(page) => help::render_page(&tr, &plugins, page, help_config.page_size),
+            HelpQuery::All => help::render_all(&tr, &plugins),
+            HelpQuery::Search(keyword) => help::render_search(&tr, &plugins, &keyword),
+            HelpQuery::Plugin(name) => match plugins.iter().find(|p| p.name() == name) {
+                Some(plugin) => help::render_plugin(&tr, *plugin),
+                None => tr.t("help.not_found"),
+            },
+        };
+        let is_long = resp.lines().count() > help_config.max_inline_lines;
+        if message_type == PluginSenario::Private || !is_long {
+            self.reply(&event, resp).await?;
+            return Ok(PluginFlow::Consumed);
+        }
+        match help_config.delivery {
+            HelpDelivery::Inline => {
+                self.reply(&event, resp).await?;
             }
-            _ => {
-                for plugin in self.plugins.iter() {
-                    if (plugin.senario() == message_type || plugin.senario() == PluginSenario::Both)
-                        && plugin.name() == content
-                    {
-                        resp.push_str(
-                            format!(
-                                "{}: {}\r\n\r\n{}\r\n",
-                                plugin.name(),
-                                plugin.description(),
-                                plugin.help()
-                            )
-                            .as_str(),
-                        );
-                    }
-                }
-                if resp.is_empty() {
-                    resp.push_str("未找到插件或插件不可用");
-                }
+            HelpDelivery::Private => {
+                self.api_request::<serde_json::Value>(
+                    "send_private_msg",
+                    json!({
+                        "user_id": event.user_id,
+                        "group_id": event.group_id,
+                        "message": resp,
+                    }),
+                )
+                .await?;
+                self.reply_with(
+                    &event,
+                    tr.t("help.sent_privately"),
+                    ReplyOptions {
+                        quote: true,
+                        at_sender: false,
+                    },
+                )
+                .await?;
+            }
+            HelpDelivery::Forward => {
+                let nodes: Vec<String> = resp.split("\r\n\r\n").map(str::to_string).collect();
+                self.reply_forward(&event, &tr.t("help.title"), nodes)
+                    .await?;
             }
         }
-        let resp = match content.as_str() {
-            "" => format!("用法:\r\n>help [插件名]\r\n\r\n插件列表:\r\n{resp}"),
-            _ => resp,
-        };
-        match message_type {
-            PluginSenario::Private => {
-                self.api_request(
-                    "send_msg",
-                    SendPrivateMsgReq {
-                        user_id: user_id.unwrap(),
-                        message: resp,
+        Ok(PluginFlow::Consumed)
+    }
+    /// Sends `nodes` as one forward message into the chat `event` came from.
+    pub async fn reply_forward(
+        &self,
+        event: &CQEvent,
+        name: &str,
+        nodes: Vec<String>,
+    ) -> BotResult<()> {
+        let nodes: Vec<serde_json::Value> = nodes
+            .into_iter()
+            .map(|content| {
+                json!({
+                    "type": "node",
+                    "data": {
+                        "name": name,
+                        "uin": event.self_id,
+                        "content": content,
                     },
+                })
+            })
+            .collect();
+        match (event.message_type.as_deref(), event.group_id) {
+            (Some("private"), _) | (_, None) => {
+                self.api_request::<serde_json::Value>(
+                    "send_private_forward_msg",
+                    json!({ "user_id": event.user_id, "messages": nodes }),
                 )
                 .await?;
             }
-            PluginSenario::Group => {
-                self.api_request(
-                    "send_group_msg",
-                    SendGroupMsgReq {
-                        group_id: group_id.unwrap(),
-                        message: resp,
-                    },
+            (_, Some(group_id)) => {
+                self.api_request::<serde_json::Value>(
+                    "send_group_forward_msg",
+                    json!({ "group_id": group_id, "messages": nodes }),
                 )
                 .await?;
             }
-            _ => unreachable!(),
         }
         Ok(())
     }
+    /// Replies to `event` in the context it came from: a private message is
+    /// answered privately, anything carrying a `group_id` goes to the group.
+    pub async fn reply(&self, event: &CQEvent, message: impl Into<String>) -> BotResult<i32> {
+        self.reply_with(event, message, ReplyOptions::default())
+            .await
+    }
+    pub async fn reply_with(
+        &self,
+        event: &CQEvent,
+        message: impl Into<String>,
+        options: ReplyOptions,
+    ) -> BotResult<i32> {
+        let is_private = match event.message_type.as_deref() {
+            Some("private") => true,
+            Some(_) => false,
+            None => event.group_id.is_none(),
+        };
+        let mut prefix = String::new();
+        if options.quote {
+            if let Some(message_id) = event.message_id {
+                prefix.push_str(&format!("[CQ:reply,id={message_id}]"));
+            }
+        }
+        if options.at_sender && !is_private {
+            if let Some(user_id) = event.user_id {
+                prefix.push_str(&format!("[CQ:at,qq={user_id}] "));
+            }
+        }
+        let message = prefix + message.into().as_str();
+        let req = if is_private {
+            SendMsgReq {
+                message_type: "private",
+                user_id: event.user_id,
+                group_id: None,
+                message,
+            }
+        } else {
+            SendMsgReq {
+                message_type: "group",
+                user_id: None,
+                group_id: event.group_id,
+                message,
+            }
+        };
+        self.api_request::<SentMessage>("send_msg", req)
+            .await
+            .map(|sent| sent.message_id)
+    }
 }
 
 unsafe impl Sync for Bot {}
 
-#[derive(Serialize)]
-struct SendGroupMsgReq {
-    group_id: i64,
-    message: String,
+#[derive(Default, Clone, Copy)]
+pub struct ReplyOptions {
+    /// quote the triggering message with a reply segment
+    pub quote: bool,
+    /// @ the sender, ignored in private chats
+    pub at_sender: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    retcode: i64,
+    #[serde(default)]
+    data: serde_json::Value,
+    msg: Option<String>,
+    wording: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SentMessage {
+    message_id: i32,
 }
 
 #[derive(Serialize)]
-struct SendPrivateMsgReq {
-    user_id: i64,
+struct SendMsgReq {
+    message_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_id: Option<i64>,
     message: String,
 }
@@ -0,0 +1,129 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    // more messages than this into one group within the window trips the breaker
+    pub max_messages: usize,
+    pub window_seconds: u64,
+    // how long the bot stays silent in the group once tripped
+    pub cooldown_seconds: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            max_messages: 20,
+            window_seconds: 60,
+            cooldown_seconds: 300,
+        }
+    }
+}
+
+#[derive(Default)]
+struct GroupState {
+    sent_at: VecDeque<Instant>,
+    open_until: Option<Instant>,
+}
+
+// stops the bot from flooding a group, e.g. when two bots repeat each other
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    groups: Mutex<HashMap<i64, GroupState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+    // records a message about to be sent, false if it must not be sent
+    pub fn allow(&self, group_id: i64) -> bool {
+        if self.config.max_messages == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut groups = self.groups.lock().unwrap();
+        let state = groups.entry(group_id).or_default();
+        if let Some(open_until) = state.open_until {
+            if now < open_until {
+                return false;
+            }
+            state.open_until = None;
+            state.sent_at.clear();
+        }
+        let window = Duration::from_secs(self.config.window_seconds);
+        while let Some(sent_at) = state.sent_at.front() {
+            if now.duration_since(*sent_at) <= window {
+                break;
+            }
+            state.sent_at.pop_front();
+        }
+        if state.sent_at.len() >= self.config.max_messages {
+            warn!(
+                "more than {} messages sent to group {} within {}s, muting for {}s",
+                self.config.max_messages,
+                group_id,
+                self.config.window_seconds,
+                self.config.cooldown_seconds
+            );
+            state.open_until = Some(now + Duration::from_secs(self.config.cooldown_seconds));
+            return false;
+        }
+        state.sent_at.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(max_messages: usize, cooldown_seconds: u64) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            max_messages,
+            window_seconds: 60,
+            cooldown_seconds,
+        })
+    }
+
+    #[test]
+    fn trips_after_max_messages_in_the_window() {
+        let breaker = breaker(2, 300);
+        assert!(breaker.allow(10));
+        assert!(breaker.allow(10));
+        assert!(!breaker.allow(10));
+        // still open for the cooldown
+        assert!(!breaker.allow(10));
+    }
+
+    #[test]
+    fn groups_trip_separately() {
+        let breaker = breaker(1, 300);
+        assert!(breaker.allow(10));
+        assert!(!breaker.allow(10));
+        assert!(breaker.allow(20));
+    }
+
+    #[test]
+    fn closes_after_the_cooldown() {
+        let breaker = breaker(1, 0);
+        assert!(breaker.allow(10));
+        assert!(!breaker.allow(10));
+        assert!(breaker.allow(10));
+    }
+
+    #[test]
+    fn zero_max_messages_never_trips() {
+        let breaker = breaker(0, 300);
+        assert!((0..100).all(|_| breaker.allow(10)));
+    }
+}
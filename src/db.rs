@@ -0,0 +1,20 @@
+use std::str::FromStr;
+
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    SqlitePool,
+};
+
+use crate::error::BotResult;
+
+static MIGRATOR: Migrator = sqlx::migrate!("src/sql/migrations");
+
+// opens the database at `url`, creating it when missing, and brings its
+// schema up to date
+pub async fn connect(url: &str) -> BotResult<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+    let db = SqlitePoolOptions::new().connect_with(options).await?;
+    MIGRATOR.run(&db).await.map_err(sqlx::Error::from)?;
+    Ok(db)
+}
@@ -0,0 +1,71 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BotError {
+    #[error("request to go-cqhttp failed: {0}")]
+    Transport(reqwest::Error),
+    // any other outgoing request, like saucenao or media downloads
+    #[error("http request failed: {0}")]
+    Http(reqwest::Error),
+    #[error("api {api} returned retcode {retcode}: {msg}")]
+    Api {
+        api: String,
+        retcode: i64,
+        msg: String,
+    },
+    #[error("failed to decode response: {0}")]
+    Decode(String),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("too many messages sent to group {0}, muted by the circuit breaker")]
+    CircuitOpen(i64),
+    #[error("message dropped, the plugin is muted by a middleware")]
+    Muted,
+    #[error("failed to render image: {0}")]
+    Render(String),
+    #[error("script error: {0}")]
+    Script(String),
+    #[cfg(feature = "wasm")]
+    #[error("wasm plugin error: {0}")]
+    Wasm(String),
+    #[error("invalid config: {0}")]
+    Config(String),
+    // shown to the user as is
+    #[error("{0}")]
+    UserInput(String),
+}
+
+impl BotError {
+    // for errors of requests to go-cqhttp itself
+    pub fn transport(err: reqwest::Error) -> Self {
+        match BotError::from(err) {
+            BotError::Http(err) => BotError::Transport(err),
+            err => err,
+        }
+    }
+}
+
+impl From<reqwest::Error> for BotError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            BotError::Decode(err.to_string())
+        } else {
+            BotError::Http(err)
+        }
+    }
+}
+
+impl From<serde_json::Error> for BotError {
+    fn from(err: serde_json::Error) -> Self {
+        BotError::Decode(err.to_string())
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl From<wasmtime::Error> for BotError {
+    fn from(err: wasmtime::Error) -> Self {
+        BotError::Wasm(err.to_string())
+    }
+}
+
+pub type BotResult<T> = Result<T, BotError>;
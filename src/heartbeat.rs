@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::models::CQEvent;
+
+struct AccountStatus {
+    last_seen: Instant,
+    interval: Duration,
+    online: bool,
+    // went offline at least once since it was first seen
+    dropped: bool,
+}
+
+pub enum StatusChange {
+    Connected(i64),
+    // online again after a Disconnected
+    Reconnected(i64),
+    Disconnected(i64),
+}
+
+impl AccountStatus {
+    fn connected(&self, self_id: i64) -> StatusChange {
+        match self.dropped {
+            true => StatusChange::Reconnected(self_id),
+            false => StatusChange::Connected(self_id),
+        }
+    }
+}
+
+// tracks heartbeat and lifecycle meta events per self_id
+pub struct HeartbeatMonitor {
+    accounts: Mutex<HashMap<i64, AccountStatus>>,
+    // heartbeats that may be missed before an account counts as offline
+    allowed_misses: u32,
+}
+
+impl HeartbeatMonitor {
+    pub fn new(allowed_misses: u32) -> Self {
+        HeartbeatMonitor {
+            accounts: Mutex::new(HashMap::new()),
+            allowed_misses: allowed_misses.max(1),
+        }
+    }
+    pub fn observe(&self, event: &CQEvent) -> Option<StatusChange> {
+        let mut accounts = self.accounts.lock().unwrap();
+        match event.meta_event_type.as_deref() {
+            Some("heartbeat") => {
+                let interval = Duration::from_millis(event.interval.unwrap_or(5000).max(0) as u64);
+                let status = accounts.entry(event.self_id).or_insert(AccountStatus {
+                    last_seen: Instant::now(),
+                    interval,
+                    online: false,
+                    dropped: false,
+                });
+                status.last_seen = Instant::now();
+                status.interval = interval;
+                if status.online {
+                    return None;
+                }
+                status.online = true;
+                Some(status.connected(event.self_id))
+            }
+            Some("lifecycle") => {
+                let online = event.sub_type.as_deref() != Some("disable");
+                let status = accounts.entry(event.self_id).or_insert(AccountStatus {
+                    last_seen: Instant::now(),
+                    interval: Duration::from_secs(5),
+                    online: !online,
+                    dropped: false,
+                });
+                status.last_seen = Instant::now();
+                if status.online == online {
+                    return None;
+                }
+                status.online = online;
+                if online {
+                    Some(status.connected(event.self_id))
+                } else {
+                    status.dropped = true;
+                    Some(StatusChange::Disconnected(event.self_id))
+                }
+            }
+            _ => None,
+        }
+    }
+    // accounts whose heartbeats stopped since the last check
+    pub fn expired(&self) -> Vec<StatusChange> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let mut ret = Vec::new();
+        for (self_id, status) in accounts.iter_mut() {
+            if status.online && status.last_seen.elapsed() > status.interval * self.allowed_misses {
+                status.online = false;
+                status.dropped = true;
+                ret.push(StatusChange::Disconnected(*self_id));
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat(interval: i64) -> CQEvent {
+        serde_json::from_value(serde_json::json!({
+            "time": 0,
+            "self_id": 1,
+            "post_type": "meta_event",
+            "meta_event_type": "heartbeat",
+            "interval": interval,
+        }))
+        .unwrap()
+    }
+
+    fn lifecycle(sub_type: &str) -> CQEvent {
+        serde_json::from_value(serde_json::json!({
+            "time": 0,
+            "self_id": 1,
+            "post_type": "meta_event",
+            "meta_event_type": "lifecycle",
+            "sub_type": sub_type,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn first_heartbeat_connects() {
+        let monitor = HeartbeatMonitor::new(3);
+        assert!(matches!(
+            monitor.observe(&heartbeat(5000)),
+            Some(StatusChange::Connected(1))
+        ));
+        assert!(monitor.observe(&heartbeat(5000)).is_none());
+        assert!(monitor.expired().is_empty());
+    }
+
+    #[test]
+    fn missed_heartbeats_disconnect_until_the_next_one() {
+        let monitor = HeartbeatMonitor::new(1);
+        monitor.observe(&heartbeat(1));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(matches!(
+            monitor.expired()[..],
+            [StatusChange::Disconnected(1)]
+        ));
+        // reported once
+        assert!(monitor.expired().is_empty());
+        assert!(matches!(
+            monitor.observe(&heartbeat(1)),
+            Some(StatusChange::Reconnected(1))
+        ));
+    }
+
+    #[test]
+    fn lifecycle_events_connect_and_disconnect() {
+        let monitor = HeartbeatMonitor::new(3);
+        assert!(matches!(
+            monitor.observe(&lifecycle("connect")),
+            Some(StatusChange::Connected(1))
+        ));
+        assert!(monitor.observe(&heartbeat(5000)).is_none());
+        assert!(matches!(
+            monitor.observe(&lifecycle("disable")),
+            Some(StatusChange::Disconnected(1))
+        ));
+        assert!(matches!(
+            monitor.observe(&lifecycle("enable")),
+            Some(StatusChange::Reconnected(1))
+        ));
+    }
+}
@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{i18n::Translator, models::Plugin};
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HelpDelivery {
+    // always answer in the chat the help was asked in
+    Inline,
+    // DM long help to the user who asked for it
+    Private,
+    // send long help as a forward message
+    Forward,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HelpConfig {
+    pub page_size: usize,
+    // help asked in a group with more lines than this is sent via `delivery`
+    pub max_inline_lines: usize,
+    pub delivery: HelpDelivery,
+}
+
+impl Default for HelpConfig {
+    fn default() -> Self {
+        HelpConfig {
+            page_size: 8,
+            max_inline_lines: 15,
+            delivery: HelpDelivery::Forward,
+        }
+    }
+}
+
+pub enum HelpQuery {
+    Page(usize),
+    All,
+    Search(String),
+    Plugin(String),
+}
+
+impl HelpQuery {
+    pub fn parse(content: &str) -> Self {
+        let content = content.trim();
+        if content.is_empty() {
+            return HelpQuery::Page(1);
+        }
+        if content == "all" {
+            return HelpQuery::All;
+        }
+        if let Ok(page) = content.parse::<usize>() {
+            return HelpQuery::Page(page.max(1));
+        }
+        match content.strip_prefix("search") {
+            Some(keyword) if keyword.is_empty() || keyword.starts_with(char::is_whitespace) => {
+                HelpQuery::Search(keyword.trim().to_lowercase())
+            }
+            _ => HelpQuery::Plugin(content.to_string()),
+        }
+    }
+}
+
+type PluginRef<'a> = &'a (dyn Plugin + Send + Sync);
+
+fn description(tr: &Translator, plugin: PluginRef) -> String {
+    tr.t_or(
+        &format!("{}.description", plugin.name()),
+        plugin.description(),
+    )
+}
+
+fn help(tr: &Translator, plugin: PluginRef) -> String {
+    tr.t_or(&format!("{}.help", plugin.name()), plugin.help())
+}
+
+fn entry(tr: &Translator, plugin: PluginRef) -> String {
+    let passive = match plugin.is_passive() {
+        true => format!(" {}", tr.t("help.passive_tag")),
+        false => String::new(),
+    };
+    format!(
+        "  {:10}\t{}{}\r\n",
+        plugin.name(),
+        description(tr, plugin),
+        passive
+    )
+}
+
+// plugins grouped by category, categories in order of first appearance
+fn grouped<'a>(plugins: &[PluginRef<'a>]) -> Vec<PluginRef<'a>> {
+    let mut categories: Vec<&str> = Vec::new();
+    for plugin in plugins {
+        if !categories.contains(&plugin.category()) {
+            categories.push(plugin.category());
+        }
+    }
+    let mut ret = Vec::new();
+    for category in categories {
+        ret.extend(plugins.iter().filter(|p| p.category() == category));
+    }
+    ret
+}
+
+fn category(tr: &Translator, plugin: PluginRef) -> String {
+    tr.t_or(
+        &format!("category.{}", plugin.category()),
+        plugin.category(),
+    )
+}
+
+fn render_list(tr: &Translator, plugins: &[PluginRef]) -> String {
+    let mut resp = String::new();
+    let mut current = "";
+    for plugin in plugins {
+        if plugin.category() != current {
+            current = plugin.category();
+            resp.push_str(format!("【{}】\r\n", category(tr, *plugin)).as_str());
+        }
+        resp.push_str(entry(tr, *plugin).as_str());
+    }
+    resp
+}
+
+pub fn render_page(
+    tr: &Translator,
+    plugins: &[PluginRef],
+    page: usize,
+    page_size: usize,
+) -> String {
+    if plugins.is_empty() {
+        return tr.t("help.no_plugins");
+    }
+    let plugins = grouped(plugins);
+    let page_size = page_size.max(1);
+    let pages = plugins.len().div_ceil(page_size);
+    let page = page.min(pages);
+    let start = (page - 1) * page_size;
+    let end = (start + page_size).min(plugins.len());
+    format!(
+        "{}\r\n\r\n{}\r\n{}",
+        tr.t("help.usage"),
+        tr.t_args("help.list_page", &[("page", &page), ("pages", &pages)]),
+        render_list(tr, &plugins[start..end])
+    )
+}
+
+pub fn render_all(tr: &Translator, plugins: &[PluginRef]) -> String {
+    if plugins.is_empty() {
+        return tr.t("help.no_plugins");
+    }
+    format!(
+        "{}\r\n{}",
+        tr.t("help.list"),
+        render_list(tr, &grouped(plugins))
+    )
+}
+
+pub fn render_search(tr: &Translator, plugins: &[PluginRef], keyword: &str) -> String {
+    if keyword.is_empty() {
+        return tr.t("help.search_usage");
+    }
+    let matched: Vec<PluginRef> = plugins
+        .iter()
+        .filter(|plugin| {
+            [
+                plugin.name().to_string(),
+                description(tr, **plugin),
+                help(tr, **plugin),
+            ]
+            .iter()
+            .map(String::as_str)
+            .chain(plugin.examples().iter().copied())
+            .any(|text| text.to_lowercase().contains(keyword))
+        })
+        .copied()
+        .collect();
+    if matched.is_empty() {
+        return tr.t_args("help.no_match", &[("keyword", &keyword)]);
+    }
+    format!(
+        "{}\r\n{}",
+        tr.t_args("help.matched", &[("keyword", &keyword)]),
+        render_list(tr, &grouped(&matched))
+    )
+}
+
+pub fn render_plugin(tr: &Translator, plugin: PluginRef) -> String {
+    let mut resp = format!(
+        "{}: {}\r\n{}\r\n",
+        plugin.name(),
+        description(tr, plugin),
+        tr.t_args("help.category", &[("category", &category(tr, plugin))])
+    );
+    if plugin.is_passive() {
+        resp.push_str(format!("{}\r\n", tr.t("help.passive")).as_str());
+    }
+    let help = help(tr, plugin);
+    if !help.is_empty() {
+        resp.push_str(format!("\r\n{help}\r\n").as_str());
+    }
+    if !plugin.examples().is_empty() {
+        resp.push_str(format!("\r\n{}\r\n", tr.t("help.examples")).as_str());
+        for example in plugin.examples() {
+            resp.push_str(format!("  {example}\r\n").as_str());
+        }
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pages() {
+        assert!(matches!(HelpQuery::parse(""), HelpQuery::Page(1)));
+        assert!(matches!(HelpQuery::parse(" 3 "), HelpQuery::Page(3)));
+        assert!(matches!(HelpQuery::parse("0"), HelpQuery::Page(1)));
+        assert!(matches!(HelpQuery::parse("all"), HelpQuery::All));
+    }
+
+    #[test]
+    fn parses_searches() {
+        assert!(matches!(
+            HelpQuery::parse("search Sauce"),
+            HelpQuery::Search(keyword) if keyword == "sauce"
+        ));
+        assert!(matches!(
+            HelpQuery::parse("search"),
+            HelpQuery::Search(keyword) if keyword.is_empty()
+        ));
+    }
+
+    #[test]
+    fn anything_else_names_a_plugin() {
+        assert!(matches!(
+            HelpQuery::parse("sauce"),
+            HelpQuery::Plugin(name) if name == "sauce"
+        ));
+        assert!(matches!(
+            HelpQuery::parse("searchbot"),
+            HelpQuery::Plugin(name) if name == "searchbot"
+        ));
+    }
+}
@@ -0,0 +1,125 @@
+use std::{collections::HashMap, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::CQEvent;
+
+const CATALOGS: &[(&str, &str)] = &[
+    ("zh", include_str!("../locales/zh.toml")),
+    ("en", include_str!("../locales/en.toml")),
+];
+
+#[derive(Deserialize, Serialize)]
+pub struct I18nConfig {
+    pub default_locale: String,
+    // group id -> locale
+    #[serde(default)]
+    pub groups: HashMap<String, String>,
+    // locale -> key -> text, takes precedence over the built-in catalogs
+    #[serde(default)]
+    pub overrides: HashMap<String, HashMap<String, String>>,
+}
+
+impl Default for I18nConfig {
+    fn default() -> Self {
+        I18nConfig {
+            default_locale: "zh".to_string(),
+            groups: HashMap::new(),
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+pub struct Catalog {
+    config: I18nConfig,
+    // locale -> dotted key -> text
+    messages: HashMap<String, HashMap<String, String>>,
+}
+
+fn flatten(prefix: &str, value: toml::Value, out: &mut HashMap<String, String>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let key = match prefix {
+                    "" => key,
+                    _ => format!("{prefix}.{key}"),
+                };
+                flatten(&key, value, out);
+            }
+        }
+        toml::Value::String(text) => {
+            out.insert(prefix.to_string(), text);
+        }
+        other => {
+            out.insert(prefix.to_string(), other.to_string());
+        }
+    }
+}
+
+impl Catalog {
+    pub fn new(config: I18nConfig) -> Self {
+        let mut messages = HashMap::new();
+        for (locale, src) in CATALOGS {
+            let value: toml::Value = toml::from_str(src).expect("built-in catalog is invalid");
+            let mut flat = HashMap::new();
+            flatten("", value, &mut flat);
+            messages.insert(locale.to_string(), flat);
+        }
+        Catalog { config, messages }
+    }
+    pub fn default_locale(&self) -> &str {
+        &self.config.default_locale
+    }
+    pub fn locale_of(&self, event: &CQEvent) -> &str {
+        event
+            .group_id
+            .and_then(|group_id| self.config.groups.get(&group_id.to_string()))
+            .unwrap_or(&self.config.default_locale)
+    }
+    pub fn translator<'a>(&'a self, locale: &'a str) -> Translator<'a> {
+        Translator {
+            catalog: self,
+            locale,
+        }
+    }
+    fn lookup(&self, locale: &str, key: &str) -> Option<&str> {
+        let overridden = self
+            .config
+            .overrides
+            .get(locale)
+            .and_then(|texts| texts.get(key));
+        overridden
+            .or_else(|| self.messages.get(locale).and_then(|texts| texts.get(key)))
+            .map(String::as_str)
+    }
+}
+
+pub struct Translator<'a> {
+    catalog: &'a Catalog,
+    locale: &'a str,
+}
+
+impl Translator<'_> {
+    /// Looks `key` up in the chat's locale, then the default locale, and
+    /// falls back to `default` when neither has it.
+    pub fn t_or(&self, key: &str, default: &str) -> String {
+        let default_locale = self.catalog.config.default_locale.as_str();
+        self.catalog
+            .lookup(self.locale, key)
+            .or_else(|| self.catalog.lookup(default_locale, key))
+            .or_else(|| self.catalog.lookup("zh", key))
+            .unwrap_or(default)
+            .to_string()
+    }
+    pub fn t(&self, key: &str) -> String {
+        self.t_or(key, key)
+    }
+    // replaces `{name}` placeholders with the matching argument
+    pub fn t_args(&self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+        let mut text = self.t(key);
+        for (name, value) in args {
+            text = text.replace(&format!("{{{name}}}"), &value.to_string());
+        }
+        text
+    }
+}
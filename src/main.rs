@@ -1,30 +1,49 @@
 mod bot;
+mod breaker;
+mod db;
+mod error;
+mod heartbeat;
+mod help;
+mod i18n;
+mod members;
+mod middlewares;
 mod models;
 mod plugins;
-use actix_web::{post, web, App, HttpResponse, HttpServer, Responder};
+mod queue;
+mod render;
+mod session;
+mod storage;
+use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
 use bot::Bot;
 use log::{info, warn};
+use middlewares::*;
 use models::AppConfig;
 use plugins::*;
-use tokio::sync::mpsc::{self, Sender};
+use std::sync::Arc;
 
 use crate::models::CQEvent;
+use crate::queue::{EventQueue, PushResult};
 
 #[post("/")]
-async fn handle_event(event: web::Json<CQEvent>, tx: web::Data<Sender<CQEvent>>) -> impl Responder {
+async fn handle_event(
+    event: web::Json<CQEvent>,
+    queue: web::Data<EventQueue>,
+    bot: web::Data<Bot>,
+) -> impl Responder {
     let event = event.into_inner();
-    // match event.post_type.as_str() {
-    //     "message" => bot.handle_message(event).await,
-    //     "request" => bot.handle_request(event).await,
-    //     "notice" => bot.handle_notice(event).await,
-    //     "meta_event" => bot.handle_meta_event(event).await,
-    //     _ => (),
-    // }
-    if event.post_type.as_str() == "meta_event" {
+    if event.post_type == "meta_event" {
+        bot.into_inner().observe_meta(&event);
         return HttpResponse::NoContent().finish();
     }
-    tx.send(event).await.unwrap();
-    return HttpResponse::NoContent().finish();
+    match queue.push(event) {
+        PushResult::Queued | PushResult::Shed => HttpResponse::NoContent().finish(),
+        PushResult::Rejected => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+#[get("/metrics")]
+async fn metrics(queue: web::Data<EventQueue>) -> impl Responder {
+    HttpResponse::Ok().json(queue.stats())
 }
 
 #[tokio::main]
@@ -36,28 +55,35 @@ async fn main() {
         std::fs::write("config.toml", &ret).unwrap();
         ret
     });
-    let cfg: AppConfig = toml::from_str(&cfg_str).expect("config.toml is invalid");
+    let mut cfg: AppConfig = toml::from_str(&cfg_str).expect("config.toml is invalid");
+    cfg.migrate();
     let listen_addr = cfg.bot.listen_addr.clone();
-    let (tx, rx) = mpsc::channel(100);
-    let mut bot = Bot::new(rx, cfg.bot);
+    let queue = web::Data::new(EventQueue::new(cfg.bot.queue_size));
+    let mut bot = Bot::new(queue.clone().into_inner(), cfg.bot);
 
-    bot.register_plugin(EchoPlugin::new(cfg.plugins.echo));
-    bot.register_plugin(QuestionPlugin::new(cfg.plugins.question));
-    bot.register_plugin(ArchivePlugin::new(cfg.plugins.archive));
-    bot.register_plugin(SaucePlugin::new(cfg.plugins.sauce));
-    bot.register_plugin(RandintPlugin::new(cfg.plugins.randint));
-    bot.register_plugin(HOKpPlugin::new(cfg.plugins.hokp));
-    bot.register_plugin(RepeatPlugin::new(cfg.plugins.repeat));
-    bot.register_plugin(IntegralPlugin::new(cfg.plugins.integral).await);
+    bot.register_middleware(SenarioMiddleware);
+    bot.register_middleware(AccessMiddleware::new(cfg.middlewares.access));
+    bot.register_middleware(CooldownMiddleware::new(cfg.middlewares.cooldown));
+    if cfg.middlewares.logging {
+        bot.register_middleware(LoggingMiddleware);
+    }
 
-    let bot_thread = tokio::spawn(async move {
-        bot.run().await;
-    });
+    let plugins = build_plugins(&cfg.plugins)
+        .await
+        .expect("failed to build plugins");
+    for plugin in plugins {
+        bot.register_plugin(plugin);
+    }
+
+    let bot = web::Data::from(Arc::new(bot));
+    let bot_thread = tokio::spawn(bot.clone().into_inner().run());
     info!("bot started.");
     HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(tx.clone()))
+            .app_data(queue.clone())
+            .app_data(bot.clone())
             .service(handle_event)
+            .service(metrics)
     })
     .bind(listen_addr)
     .unwrap()
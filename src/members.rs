@@ -0,0 +1,89 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::models::CQEvent;
+
+#[derive(Clone, Deserialize)]
+pub struct Member {
+    pub user_id: i64,
+    pub nickname: String,
+    #[serde(default)]
+    pub card: String,
+}
+
+impl Member {
+    // the group card, or the nickname when it is not set
+    pub fn display_name(&self) -> &str {
+        match self.card.as_str() {
+            "" => &self.nickname,
+            card => card,
+        }
+    }
+}
+
+pub type GroupMembers = Arc<HashMap<i64, Member>>;
+
+struct CachedGroup {
+    fetched_at: Instant,
+    members: GroupMembers,
+}
+
+// member lists of groups fetched with `get_group_member_list`, dropped when
+// they are older than `ttl` or a notice says the group changed. They are kept
+// per (self_id, group_id), accounts in the same group may see it differently
+pub struct MemberCache {
+    groups: Mutex<HashMap<(i64, i64), CachedGroup>>,
+    ttl: Duration,
+}
+
+impl MemberCache {
+    pub fn new(ttl: Duration) -> Self {
+        MemberCache {
+            groups: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+    pub fn get(&self, self_id: i64, group_id: i64) -> Option<GroupMembers> {
+        let groups = self.groups.lock().unwrap();
+        groups
+            .get(&(self_id, group_id))
+            .filter(|group| group.fetched_at.elapsed() < self.ttl)
+            .map(|group| group.members.clone())
+    }
+    pub fn insert(&self, self_id: i64, group_id: i64, members: Vec<Member>) -> GroupMembers {
+        let members: GroupMembers = Arc::new(
+            members
+                .into_iter()
+                .map(|member| (member.user_id, member))
+                .collect(),
+        );
+        self.groups.lock().unwrap().insert(
+            (self_id, group_id),
+            CachedGroup {
+                fetched_at: Instant::now(),
+                members: members.clone(),
+            },
+        );
+        members
+    }
+    pub fn observe(&self, event: &CQEvent) {
+        if event.post_type != "notice" {
+            return;
+        }
+        let changed = matches!(
+            event.notice_type.as_deref(),
+            Some("group_increase") | Some("group_decrease") | Some("group_card")
+        );
+        if let (true, Some(group_id)) = (changed, event.group_id) {
+            self.groups
+                .lock()
+                .unwrap()
+                .remove(&(event.self_id, group_id));
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bot::Bot,
+    models::{CQEvent, Middleware, MiddlewareFlow, Plugin},
+};
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct AccessRule {
+    // plugins the rule applies to, all plugins when empty
+    #[serde(default)]
+    pub plugins: Vec<String>,
+    // when not empty, group events from other groups are dropped
+    #[serde(default)]
+    pub group_whitelist: Vec<i64>,
+    #[serde(default)]
+    pub group_blacklist: Vec<i64>,
+    #[serde(default)]
+    pub user_blacklist: Vec<i64>,
+}
+
+impl AccessRule {
+    fn applies_to(&self, plugin: &str) -> bool {
+        self.plugins.is_empty() || self.plugins.iter().any(|name| name == plugin)
+    }
+    fn allows(&self, event: &CQEvent) -> bool {
+        if let Some(group_id) = event.group_id {
+            if !self.group_whitelist.is_empty() && !self.group_whitelist.contains(&group_id) {
+                return false;
+            }
+            if self.group_blacklist.contains(&group_id) {
+                return false;
+            }
+        }
+        match event.user_id {
+            Some(user_id) => !self.user_blacklist.contains(&user_id),
+            None => true,
+        }
+    }
+}
+
+pub struct AccessMiddleware {
+    rules: Vec<AccessRule>,
+}
+
+impl AccessMiddleware {
+    pub fn new(rules: Vec<AccessRule>) -> Self {
+        AccessMiddleware { rules }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for AccessMiddleware {
+    fn name(&self) -> &'static str {
+        "access"
+    }
+    async fn before(
+        &self,
+        event: &mut CQEvent,
+        plugin: &(dyn Plugin + Send + Sync),
+        _bot: &Bot,
+    ) -> MiddlewareFlow {
+        let denied = self
+            .rules
+            .iter()
+            .any(|rule| rule.applies_to(plugin.name()) && !rule.allows(event));
+        if denied {
+            return MiddlewareFlow::Skip;
+        }
+        let whitelisted = self.rules.iter().any(|rule| {
+            rule.applies_to(plugin.name())
+                && event
+                    .group_id
+                    .is_some_and(|group_id| rule.group_whitelist.contains(&group_id))
+        });
+        if whitelisted {
+            event.tags.insert("whitelisted".to_string());
+        }
+        MiddlewareFlow::Continue
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use log::debug;
+use tokio::sync::Mutex;
+
+use crate::{
+    bot::Bot,
+    models::{CQEvent, HandleOutcome, Middleware, MiddlewareFlow, Plugin},
+};
+
+// silences a plugin in a chat for a while after it has sent something there,
+// the plugin still sees the events so it can keep its state up to date
+pub struct CooldownMiddleware {
+    sleep_seconds: HashMap<String, i64>,
+    // (plugin, chat) -> timestamp of the last message sent
+    last_sent: Mutex<HashMap<(&'static str, i64), i64>>,
+}
+
+impl CooldownMiddleware {
+    pub fn new(sleep_seconds: HashMap<String, i64>) -> Self {
+        CooldownMiddleware {
+            sleep_seconds,
+            last_sent: Mutex::new(HashMap::new()),
+        }
+    }
+    fn chat_id(event: &CQEvent) -> Option<i64> {
+        event.group_id.or(event.user_id)
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for CooldownMiddleware {
+    fn name(&self) -> &'static str {
+        "cooldown"
+    }
+    async fn before(
+        &self,
+        event: &mut CQEvent,
+        plugin: &(dyn Plugin + Send + Sync),
+        _bot: &Bot,
+    ) -> MiddlewareFlow {
+        let (sleep_seconds, chat_id) =
+            match (self.sleep_seconds.get(plugin.name()), Self::chat_id(event)) {
+                (Some(sleep_seconds), Some(chat_id)) => (*sleep_seconds, chat_id),
+                _ => return MiddlewareFlow::Continue,
+            };
+        let last_sent = self.last_sent.lock().await;
+        let last_timestamp = match last_sent.get(&(plugin.name(), chat_id)) {
+            Some(timestamp) => *timestamp,
+            None => return MiddlewareFlow::Continue,
+        };
+        let now_timestamp = chrono::Utc::now().timestamp();
+        if now_timestamp - last_timestamp < sleep_seconds {
+            debug!(
+                "plugin {} sleeping. {} seconds remaining. muting...",
+                plugin.name(),
+                sleep_seconds + last_timestamp - now_timestamp
+            );
+            return MiddlewareFlow::Mute;
+        }
+        MiddlewareFlow::Continue
+    }
+    async fn after(
+        &self,
+        event: &CQEvent,
+        plugin: &(dyn Plugin + Send + Sync),
+        outcome: &HandleOutcome,
+        _bot: &Bot,
+    ) {
+        if outcome.sent == 0 || !self.sleep_seconds.contains_key(plugin.name()) {
+            return;
+        }
+        if let Some(chat_id) = Self::chat_id(event) {
+            self.last_sent
+                .lock()
+                .await
+                .insert((plugin.name(), chat_id), chrono::Utc::now().timestamp());
+        }
+    }
+}
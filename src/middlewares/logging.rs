@@ -0,0 +1,41 @@
+use log::{info, warn};
+
+use crate::{
+    bot::Bot,
+    models::{CQEvent, HandleOutcome, Middleware, Plugin},
+};
+
+pub struct LoggingMiddleware;
+
+#[async_trait::async_trait]
+impl Middleware for LoggingMiddleware {
+    fn name(&self) -> &'static str {
+        "logging"
+    }
+    async fn after(
+        &self,
+        event: &CQEvent,
+        plugin: &(dyn Plugin + Send + Sync),
+        outcome: &HandleOutcome,
+        _bot: &Bot,
+    ) {
+        match &outcome.error {
+            None if outcome.sent > 0 => info!(
+                "plugin {} sent {} message(s) in {:?} for {} event from {:?}, tags: {:?}",
+                plugin.name(),
+                outcome.sent,
+                outcome.elapsed,
+                event.post_type,
+                event.group_id.or(event.user_id),
+                event.tags
+            ),
+            None => (),
+            Some(err) => warn!(
+                "plugin {} failed in {:?}: {}",
+                plugin.name(),
+                outcome.elapsed,
+                err
+            ),
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+mod access;
+pub use access::*;
+mod cooldown;
+pub use cooldown::*;
+mod logging;
+pub use logging::*;
+mod senario;
+pub use senario::*;
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct MiddlewaresConfig {
+    #[serde(default)]
+    pub logging: bool,
+    // kept before the tables below, an empty list is written as a plain value
+    #[serde(default)]
+    pub access: Vec<AccessRule>,
+    // plugin name -> seconds to stay silent in a chat after replying there
+    #[serde(default)]
+    pub cooldown: HashMap<String, i64>,
+}
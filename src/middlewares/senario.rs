@@ -0,0 +1,34 @@
+use crate::{
+    bot::Bot,
+    models::{CQEvent, Middleware, MiddlewareFlow, Plugin, PluginSenario},
+};
+
+// keeps message events away from plugins that do not serve the chat type
+pub struct SenarioMiddleware;
+
+#[async_trait::async_trait]
+impl Middleware for SenarioMiddleware {
+    fn name(&self) -> &'static str {
+        "senario"
+    }
+    async fn before(
+        &self,
+        event: &mut CQEvent,
+        plugin: &(dyn Plugin + Send + Sync),
+        _bot: &Bot,
+    ) -> MiddlewareFlow {
+        if event.post_type != "message" {
+            return MiddlewareFlow::Continue;
+        }
+        let senario = match event.message_type.as_deref() {
+            Some("private") => PluginSenario::Private,
+            Some("group") => PluginSenario::Group,
+            _ => return MiddlewareFlow::Skip,
+        };
+        if plugin.senario() == senario || plugin.senario() == PluginSenario::Both {
+            MiddlewareFlow::Continue
+        } else {
+            MiddlewareFlow::Skip
+        }
+    }
+}
@@ -1,30 +1,81 @@
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Duration,
+};
 
 use crate::{
     bot::{Bot, BotConfig},
-    plugins::{
-        ArchivePluginConfig, EchoPluginConfig, HOKpPluginConfig, IntegralPluginConfig,
-        QuestionPluginConfig, RandintPluginConfig, RepeatPluginConfig, SaucePluginConfig,
-    },
+    error::BotResult,
+    middlewares::{AccessRule, MiddlewaresConfig},
 };
 
-#[derive(Default, Deserialize, Serialize)]
-pub struct PluginsConfig {
-    pub archive: Option<ArchivePluginConfig>,
-    pub echo: Option<EchoPluginConfig>,
-    pub question: Option<QuestionPluginConfig>,
-    pub sauce: Option<SaucePluginConfig>,
-    pub randint: Option<RandintPluginConfig>,
-    pub hokp: Option<HOKpPluginConfig>,
-    pub repeat: Option<RepeatPluginConfig>,
-    pub integral: Option<IntegralPluginConfig>,
-}
+// `[plugins.<name>]` tables, handed to the factory registered under that name
+pub type PluginsConfig = BTreeMap<String, toml::Value>;
 
 #[derive(Default, Deserialize, Serialize)]
 pub struct AppConfig {
     pub bot: BotConfig,
+    #[serde(default)]
     pub plugins: PluginsConfig,
+    #[serde(default)]
+    pub middlewares: MiddlewaresConfig,
+}
+
+impl AppConfig {
+    // moves keys the plugins used to read themselves into the middlewares
+    // that replaced them, so old config files keep working
+    pub fn migrate(&mut self) {
+        for (name, config) in self.plugins.iter_mut() {
+            let Some(table) = config.as_table_mut() else {
+                continue;
+            };
+            if let Some(sleep_seconds) = table.remove("sleep_seconds") {
+                warn!("plugins.{name}.sleep_seconds is deprecated, use middlewares.cooldown");
+                match sleep_seconds.as_integer() {
+                    Some(sleep_seconds) => {
+                        self.middlewares
+                            .cooldown
+                            .entry(name.clone())
+                            .or_insert(sleep_seconds);
+                    }
+                    None => warn!("plugins.{name}.sleep_seconds is not an integer, ignored"),
+                }
+            }
+        }
+        // hokp used to answer only in the groups of its own whitelist
+        let hokp = self
+            .plugins
+            .get_mut("hokp")
+            .and_then(toml::Value::as_table_mut);
+        if let Some(whitelist) = hokp.and_then(|table| table.remove("whitelist")) {
+            warn!("plugins.hokp.whitelist is deprecated, use middlewares.access");
+            let group_whitelist: Vec<i64> = match whitelist.try_into() {
+                Ok(group_whitelist) => group_whitelist,
+                Err(err) => {
+                    warn!("plugins.hokp.whitelist is invalid, ignored: {err}");
+                    return;
+                }
+            };
+            if group_whitelist.is_empty() {
+                // an empty access whitelist lets every group through
+                if let Some(table) = self
+                    .plugins
+                    .get_mut("hokp")
+                    .and_then(toml::Value::as_table_mut)
+                {
+                    table.insert("enabled".to_string(), toml::Value::Boolean(false));
+                }
+                return;
+            }
+            self.middlewares.access.push(AccessRule {
+                plugins: vec!["hokp".to_string()],
+                group_whitelist,
+                ..Default::default()
+            });
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -44,6 +95,8 @@ pub struct CQEvent {
 
     // 元事件上报
     pub meta_event_type: Option<String>,
+    // heartbeat interval in milliseconds
+    pub interval: Option<i64>,
 
     // ...
     pub sub_type: Option<String>,
@@ -53,9 +106,23 @@ pub struct CQEvent {
     pub font: Option<i64>,
     pub group_id: Option<i64>,
     pub operator_id: Option<i64>,
+    pub sender: Option<CQSender>,
+
+    // set by middlewares, never reported by go-cqhttp
+    #[serde(skip)]
+    pub tags: HashSet<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CQSender {
+    pub user_id: Option<i64>,
+    pub nickname: Option<String>,
+    pub card: Option<String>,
+    // owner, admin or member, group messages only
+    pub role: Option<String>,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 // #[allow(dead_code)]
 pub enum PluginSenario {
     Private,
@@ -69,5 +136,137 @@ pub trait Plugin {
     fn description(&self) -> &'static str;
     fn help(&self) -> &'static str;
     fn senario(&self) -> PluginSenario;
-    async fn handle(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>>;
+    // heading the plugin is listed under in `>help`, translated via `category.<id>`
+    fn category(&self) -> &'static str {
+        "other"
+    }
+    fn examples(&self) -> &'static [&'static str] {
+        &[]
+    }
+    // passive plugins react to chat on their own instead of to commands
+    fn is_passive(&self) -> bool {
+        false
+    }
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow>;
+    // whether the plugin also sees messages sent by this or other known bots
+    fn accepts_bot_messages(&self) -> bool {
+        false
+    }
+    // called when go-cqhttp of the account `self_id` (re)connects
+    async fn on_connect(&self, _self_id: i64, _bot: &Bot) -> BotResult<()> {
+        Ok(())
+    }
+    // called when the account `self_id` stops sending heartbeats
+    async fn on_disconnect(&self, _self_id: i64, _bot: &Bot) -> BotResult<()> {
+        Ok(())
+    }
+}
+
+pub enum MiddlewareFlow {
+    Continue,
+    // the plugin does not see the event
+    Skip,
+    // the plugin handles the event, but nothing it sends goes out
+    Mute,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum PluginFlow {
+    // plugins after this one do not see the event
+    Consumed,
+    Pass,
+}
+
+pub struct HandleOutcome {
+    // the error the plugin returned, if any
+    pub error: Option<String>,
+    // number of messages the plugin sent while handling the event
+    pub sent: usize,
+    pub elapsed: Duration,
+}
+
+#[async_trait::async_trait]
+pub trait Middleware {
+    fn name(&self) -> &'static str;
+    async fn before(
+        &self,
+        _event: &mut CQEvent,
+        _plugin: &(dyn Plugin + Send + Sync),
+        _bot: &Bot,
+    ) -> MiddlewareFlow {
+        MiddlewareFlow::Continue
+    }
+    async fn after(
+        &self,
+        _event: &CQEvent,
+        _plugin: &(dyn Plugin + Send + Sync),
+        _outcome: &HandleOutcome,
+        _bot: &Bot,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrated(plugins: &str) -> AppConfig {
+        let mut config = AppConfig {
+            plugins: toml::from_str(plugins).unwrap(),
+            ..Default::default()
+        };
+        config.migrate();
+        config
+    }
+
+    #[test]
+    fn sleep_seconds_moves_to_cooldown() {
+        let config = migrated(
+            r#"
+            [question]
+            sleep_seconds = 5
+            [hokp]
+            sleep_seconds = "5"
+            "#,
+        );
+        assert_eq!(config.middlewares.cooldown.get("question"), Some(&5));
+        assert!(!config.middlewares.cooldown.contains_key("hokp"));
+        assert!(config.plugins["question"].get("sleep_seconds").is_none());
+        assert!(config.plugins["hokp"].get("sleep_seconds").is_none());
+    }
+
+    #[test]
+    fn configured_cooldown_wins_over_sleep_seconds() {
+        let mut config = AppConfig {
+            plugins: toml::from_str("[question]\nsleep_seconds = 5").unwrap(),
+            ..Default::default()
+        };
+        config
+            .middlewares
+            .cooldown
+            .insert("question".to_string(), 10);
+        config.migrate();
+        assert_eq!(config.middlewares.cooldown.get("question"), Some(&10));
+    }
+
+    #[test]
+    fn hokp_whitelist_becomes_an_access_rule() {
+        let config = migrated("[hokp]\nwhitelist = [10, 20]");
+        assert_eq!(config.middlewares.access.len(), 1);
+        let rule = &config.middlewares.access[0];
+        assert_eq!(rule.plugins, ["hokp"]);
+        assert_eq!(rule.group_whitelist, [10, 20]);
+        assert!(config.plugins["hokp"].get("whitelist").is_none());
+        assert!(config.plugins["hokp"].get("enabled").is_none());
+    }
+
+    #[test]
+    fn empty_hokp_whitelist_disables_hokp() {
+        let config = migrated("[hokp]\nwhitelist = []");
+        assert!(config.middlewares.access.is_empty());
+        assert_eq!(
+            config.plugins["hokp"].get("enabled"),
+            Some(&toml::Value::Boolean(false))
+        );
+    }
 }
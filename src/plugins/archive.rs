@@ -1,148 +1,772 @@
-use std::error::Error;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
-use chrono::{Local, TimeZone};
-use regex::Regex;
+use chrono::{Local, NaiveDateTime, TimeZone};
+use log::warn;
+use regex::{Captures, Regex};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sqlx::SqlitePool;
 use tokio::sync::RwLock;
 
 use crate::bot::Bot;
-use crate::models::{CQEvent, Plugin, PluginSenario};
+use crate::db;
+use crate::error::{BotError, BotResult};
+use crate::i18n::Translator;
+use crate::models::{CQEvent, Plugin, PluginFlow, PluginSenario};
+use crate::plugins::{plugin_config, BoxedPlugin, PluginFactory};
 
 #[derive(Serialize, Deserialize)]
-pub struct ArchivePluginConfig;
+pub struct ArchivePluginConfig {
+    #[serde(default = "default_db_url")]
+    db_url: String,
+    // images and voice of cached messages are downloaded here so they
+    // outlive their urls
+    #[serde(default = "default_image_dir")]
+    image_dir: String,
+    // cached messages and images older than this are dropped
+    #[serde(default = "default_retention_hours")]
+    retention_hours: i64,
+    // also cache private messages and DM their recalls to the superusers
+    #[serde(default)]
+    friend_recall: bool,
+}
+
+fn default_db_url() -> String {
+    "sqlite://archive.db".to_string()
+}
+
+fn default_image_dir() -> String {
+    "archive".to_string()
+}
+
+fn default_retention_hours() -> i64 {
+    72
+}
+
+impl Default for ArchivePluginConfig {
+    fn default() -> Self {
+        ArchivePluginConfig {
+            db_url: default_db_url(),
+            image_dir: default_image_dir(),
+            retention_hours: default_retention_hours(),
+            friend_recall: false,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum ArchiveMode {
+    Off,
+    On,
+    // recalls are recorded but not reposted
+    Quiet,
+}
+
+impl ArchiveMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            ArchiveMode::Off => "off",
+            ArchiveMode::On => "on",
+            ArchiveMode::Quiet => "quiet",
+        }
+    }
+    fn parse(mode: &str) -> Self {
+        match mode {
+            "on" => ArchiveMode::On,
+            "quiet" => ArchiveMode::Quiet,
+            _ => ArchiveMode::Off,
+        }
+    }
+}
 
 struct ArchivePluginState {
-    is_enable: bool,
+    // groups without an entry are off
+    modes: HashMap<i64, ArchiveMode>,
+    last_pruned: Option<SystemTime>,
 }
 
 pub struct ArchivePlugin {
     state: RwLock<ArchivePluginState>,
-    _config: ArchivePluginConfig,
+    db: SqlitePool,
+    image_dir: PathBuf,
+    config: ArchivePluginConfig,
 }
 
 impl ArchivePlugin {
-    pub fn new(config: Option<ArchivePluginConfig>) -> Self {
-        ArchivePlugin {
-            state: RwLock::new(ArchivePluginState { is_enable: false }),
-            _config: config.unwrap_or(ArchivePluginConfig),
+    pub async fn new(config: Option<ArchivePluginConfig>) -> BotResult<Self> {
+        let config = config.unwrap_or_default();
+        if config.db_url.is_empty() {
+            return Err(BotError::Config("archive.db_url must not be empty".into()));
         }
+        let image_dir = fs::create_dir_all(&config.image_dir)
+            .and_then(|_| fs::canonicalize(&config.image_dir))
+            .map_err(|err| BotError::Config(format!("archive.image_dir: {err}")))?;
+        let db = db::connect(&config.db_url).await?;
+        let modes = sqlx::query!(r"SELECT group_id, mode FROM archive_group")
+            .fetch_all(&db)
+            .await?
+            .into_iter()
+            .map(|row| (row.group_id, ArchiveMode::parse(&row.mode)))
+            .collect();
+        Ok(ArchivePlugin {
+            state: RwLock::new(ArchivePluginState {
+                modes,
+                last_pruned: None,
+            }),
+            db,
+            image_dir,
+            config,
+        })
     }
-    async fn archive(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
-        if !self.state.read().await.is_enable {
-            return Ok(());
-        }
-        let CQEvent {
+    async fn mode(&self, group_id: i64) -> ArchiveMode {
+        let state = self.state.read().await;
+        state
+            .modes
+            .get(&group_id)
+            .copied()
+            .unwrap_or(ArchiveMode::Off)
+    }
+    async fn set_mode(&self, group_id: i64, mode: ArchiveMode) -> BotResult<()> {
+        let mode_str = mode.as_str();
+        sqlx::query!(
+            r"INSERT OR REPLACE INTO archive_group VALUES ($1, $2)",
             group_id,
-            message_id,
-            user_id,
-            operator_id,
-            ..
-        } = event;
-        let operator_info = bot
-            .api_request(
-                "get_group_member_info",
-                json!(
-                    {
-                        "group_id": group_id.unwrap(),
-                        "user_id": operator_id.unwrap(),
-                    }
-                ),
-            )
-            .await?
-            .json::<DataExtractor>()
+            mode_str
+        )
+        .execute(&self.db)
+        .await?;
+        self.state.write().await.modes.insert(group_id, mode);
+        Ok(())
+    }
+    async fn archive(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let group_id = event.group_id.unwrap_or_default();
+        let mode = self.mode(group_id).await;
+        if mode == ArchiveMode::Off {
+            return Ok(PluginFlow::Pass);
+        }
+        let recalled = self
+            .recalled_message(group_id, event.message_id.unwrap_or_default(), bot)
+            .await?;
+        if let Some((content, sent_at)) = &recalled {
+            self.record(&event, content, *sent_at).await?;
+        }
+        if mode == ArchiveMode::Quiet {
+            return Ok(PluginFlow::Consumed);
+        }
+        let tr = bot.tr(&event);
+        let user_id = event.user_id.unwrap_or_default();
+        let operator_id = event.operator_id.unwrap_or(user_id);
+        let operator_name = Self::member_name(bot, group_id, operator_id).await;
+        let user_name = match operator_id == user_id {
+            true => tr.t("archive.self"),
+            false => Self::member_name(bot, group_id, user_id).await,
+        };
+        let (recalled_msg_content, datetime) = match recalled {
+            Some(recalled) => recalled,
+            None => {
+                let resp = tr.t_args(
+                    "archive.lost",
+                    &[("operator_name", &operator_name), ("user_name", &user_name)],
+                );
+                bot.reply(&event, resp).await?;
+                return Ok(PluginFlow::Consumed);
+            }
+        };
+        let resp = tr.t_args(
+            "archive.recalled",
+            &[
+                ("operator_name", &operator_name),
+                ("user_name", &user_name),
+                ("datetime", &datetime),
+            ],
+        );
+        bot.reply(&event, resp).await?;
+        let content = self
+            .render_segments(&recalled_msg_content, group_id, bot, &tr)
+            .await;
+        bot.reply(&event, content).await?;
+        Ok(PluginFlow::Consumed)
+    }
+    // private chats are stored as group 0, their recalls go to the superusers
+    async fn friend_archive(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        if !self.config.friend_recall {
+            return Ok(PluginFlow::Pass);
+        }
+        let user_id = event.user_id.unwrap_or_default();
+        let recalled = self
+            .recalled_message(0, event.message_id.unwrap_or_default(), bot)
+            .await?;
+        let tr = bot.tr(&event);
+        let user_name = bot
+            .api_request::<StrangerInfo>("get_stranger_info", json!({ "user_id": user_id }))
             .await
-            .unwrap()
-            .data;
-        let user_info = bot
-            .api_request(
-                "get_group_member_info",
-                json!(
-                    {
-                        "group_id": group_id.unwrap(),
-                        "user_id": user_id.unwrap(),
-                    }
-                ),
+            .map(|info| info.nickname)
+            .unwrap_or_else(|_| user_id.to_string());
+        let msg = match recalled {
+            Some((content, sent_at)) => {
+                self.record(&event, &content, sent_at).await?;
+                let header = tr.t_args(
+                    "archive.friend_recalled",
+                    &[
+                        ("user_name", &user_name),
+                        ("user_id", &user_id),
+                        ("datetime", &sent_at),
+                    ],
+                );
+                let content = self.render_segments(&content, 0, bot, &tr).await;
+                format!("{header}\r\n{content}")
+            }
+            None => tr.t_args(
+                "archive.friend_lost",
+                &[("user_name", &user_name), ("user_id", &user_id)],
+            ),
+        };
+        for owner in bot.superusers() {
+            bot.api_request::<serde_json::Value>(
+                "send_private_msg",
+                json!({ "user_id": owner, "message": msg }),
             )
-            .await?
-            .json::<DataExtractor>()
+            .await?;
+        }
+        Ok(PluginFlow::Consumed)
+    }
+    // the cached copy first, go-cqhttp only keeps messages for a while
+    async fn recalled_message(
+        &self,
+        group_id: i64,
+        message_id: i32,
+        bot: &Bot,
+    ) -> BotResult<Option<(String, NaiveDateTime)>> {
+        let cached = sqlx::query!(
+            r"SELECT content, time FROM archive_message
+            WHERE group_id = $1 AND message_id = $2",
+            group_id,
+            message_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        if let Some(row) = cached {
+            return Ok(Some((row.content, row.time)));
+        }
+        let msg_info: MsgInfo = match bot
+            .api_request("get_msg", json!({ "message_id": message_id }))
             .await
-            .unwrap()
-            .data;
-        let recalled_msg_info = bot
-            .api_request(
-                "get_msg",
-                json!(
-                    {
-                        "message_id": message_id.unwrap(),
+        {
+            Ok(msg_info) => msg_info,
+            Err(err) => {
+                warn!("recalled message {message_id} is not cached and get_msg failed: {err}");
+                return Ok(None);
+            }
+        };
+        let datetime = Local.timestamp(msg_info.time.into(), 0).naive_local();
+        // media of messages that were never cached are fetched now, while
+        // go-cqhttp still has their urls
+        let content = Self::download_media(&self.image_dir, &msg_info.message).await;
+        Ok(Some((content, datetime)))
+    }
+    async fn record(
+        &self,
+        event: &CQEvent,
+        content: &str,
+        sent_at: NaiveDateTime,
+    ) -> BotResult<()> {
+        let content = self.keep_media(content);
+        let recalled_at = Local.timestamp(event.time, 0).naive_local();
+        let group_id = event.group_id.unwrap_or_default();
+        let operator_id = event.operator_id.or(event.user_id);
+        sqlx::query!(
+            r"INSERT INTO archive_recall
+            (group_id, message_id, user_id, operator_id, sent_at, recalled_at, content)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            group_id,
+            event.message_id,
+            event.user_id,
+            operator_id,
+            sent_at,
+            recalled_at,
+            content
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+    // copies the cached media of a recalled message where pruning won't reach them
+    fn keep_media(&self, content: &str) -> String {
+        let re = Regex::new(r"\[CQ:(?P<kind>image|record),file=file://(?P<path>[^\]]+)\]").unwrap();
+        let kept_dir = self.image_dir.join("recalled");
+        re.replace_all(content, |caps: &Captures| {
+            let path = PathBuf::from(&caps["path"]);
+            let kept = match path.file_name() {
+                Some(name) if path.starts_with(&self.image_dir) => kept_dir.join(name),
+                _ => return caps[0].to_string(),
+            };
+            match fs::create_dir_all(&kept_dir).and_then(|_| fs::copy(&path, &kept)) {
+                Ok(_) => format!("[CQ:{},file=file://{}]", &caps["kind"], kept.display()),
+                Err(err) => {
+                    warn!("failed to keep {:?}: {}", path, err);
+                    caps[0].to_string()
+                }
+            }
+        })
+        .to_string()
+    }
+    async fn cache(&self, event: &CQEvent) -> BotResult<()> {
+        let group_id = match event.group_id {
+            Some(group_id) if self.mode(group_id).await != ArchiveMode::Off => group_id,
+            None if self.config.friend_recall => 0,
+            _ => return Ok(()),
+        };
+        let (message_id, user_id, content) = match event {
+            CQEvent {
+                message_id: Some(message_id),
+                user_id: Some(user_id),
+                raw_message: Some(content),
+                ..
+            } => (*message_id, *user_id, content),
+            _ => return Ok(()),
+        };
+        let time = Local.timestamp(event.time, 0).naive_local();
+        sqlx::query!(
+            r"INSERT OR REPLACE INTO archive_message VALUES ($1, $2, $3, $4, $5)",
+            group_id,
+            message_id,
+            user_id,
+            time,
+            content
+        )
+        .execute(&self.db)
+        .await?;
+        if content.contains("[CQ:image,") || content.contains("[CQ:record,") {
+            // media are downloaded in the background, a recall before they
+            // are done keeps the urls
+            let db = self.db.clone();
+            let image_dir = self.image_dir.clone();
+            let content = content.clone();
+            tokio::spawn(async move {
+                let local = Self::download_media(&image_dir, &content).await;
+                let updated = sqlx::query!(
+                    r"UPDATE archive_message SET content = $1
+                    WHERE group_id = $2 AND message_id = $3",
+                    local,
+                    group_id,
+                    message_id
+                )
+                .execute(&db)
+                .await;
+                if let Err(err) = updated {
+                    warn!("failed to cache media of message {message_id}: {err}");
+                }
+            });
+        }
+        self.prune().await
+    }
+    // points image and voice segments at local copies so they can be sent
+    // again after their urls expire, keeping the url when a download fails
+    async fn download_media(image_dir: &Path, content: &str) -> String {
+        let re = Regex::new(r"\[CQ:(?P<kind>image|record),[^\]]*\]").unwrap();
+        let mut downloaded = Vec::new();
+        for caps in re.captures_iter(content) {
+            let params = cq_params(&caps[0]);
+            let (file, url) = match (params.get("file"), params.get("url")) {
+                (Some(file), Some(url)) => (file, url),
+                _ => continue,
+            };
+            let name: String = file
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '_' || *c == '-')
+                .collect();
+            let path = image_dir.join(name);
+            if !path.exists() {
+                let bytes = match Self::fetch(url).await {
+                    Ok(bytes) => bytes,
+                    Err(err) => {
+                        warn!("failed to download {url}: {err}");
+                        continue;
                     }
-                ),
+                };
+                if let Err(err) = fs::write(&path, bytes) {
+                    warn!("failed to save {:?}: {}", path, err);
+                    continue;
+                }
+            }
+            let local = format!("[CQ:{},file=file://{}]", &caps["kind"], path.display());
+            downloaded.push((caps[0].to_string(), local));
+        }
+        let mut content = content.to_string();
+        for (segment, local) in downloaded {
+            content = content.replace(&segment, &local);
+        }
+        content
+    }
+    // reply and at segments are shown as text, raw ones would quote a message
+    // the chat can't find and ping everyone mentioned again
+    async fn render_segments(
+        &self,
+        content: &str,
+        group_id: i64,
+        bot: &Bot,
+        tr: &Translator<'_>,
+    ) -> String {
+        let re = Regex::new(r"\[CQ:(?P<kind>reply|at),[^\]]*\]").unwrap();
+        let cq_code = Regex::new(r"\[CQ:[^\]]*\]").unwrap();
+        let mut rendered = String::new();
+        let mut last = 0;
+        for caps in re.captures_iter(content) {
+            let segment = caps.get(0).unwrap();
+            rendered.push_str(&content[last..segment.start()]);
+            last = segment.end();
+            let params = cq_params(segment.as_str());
+            let target = params.get("qq").or_else(|| params.get("id"));
+            let target = match target.and_then(|target| target.parse::<i64>().ok()) {
+                Some(target) => target,
+                None if &caps["kind"] == "at" => {
+                    rendered.push_str(&tr.t("archive.at_all"));
+                    continue;
+                }
+                None => continue,
+            };
+            if &caps["kind"] == "at" {
+                let name = match group_id {
+                    0 => target.to_string(),
+                    _ => Self::member_name(bot, group_id, target).await,
+                };
+                rendered.push_str(&format!("@{name}"));
+                continue;
+            }
+            let quoted = sqlx::query!(
+                r"SELECT user_id, content FROM archive_message
+                WHERE group_id = $1 AND message_id = $2",
+                group_id,
+                target
             )
-            .await?
-            .json::<DataExtractor>()
+            .fetch_optional(&self.db)
             .await
-            .unwrap()
-            .data;
-        let recalled_msg_content = recalled_msg_info.message.unwrap();
-        let recalled_msg_timestamp = recalled_msg_info.time.unwrap();
-        let mut operator_name = operator_info.card.unwrap();
-        let mut user_name = user_info.card.unwrap();
-        if operator_name == "" {
-            operator_name = operator_info.nickname.unwrap();
-        }
-        if user_name == "" {
-            user_name = user_info.nickname.unwrap();
-        }
-        if operator_id == user_id {
-            user_name = "自己".to_string();
-        }
-        let resp = format!(
-            "{operator_name} 撤回了 {user_name} 于 {datetime} 发送的消息：",
-            datetime = Local
-                .timestamp(recalled_msg_timestamp.into(), 0)
-                .naive_local()
-        );
-        bot.api_request(
-            "send_group_msg",
-            json!(
-                {
-                    "group_id": group_id.unwrap(),
-                    "message": resp
+            .ok()
+            .flatten();
+            let quote = match quoted {
+                Some(quoted) => {
+                    let user_name = match group_id {
+                        0 => quoted.user_id.to_string(),
+                        _ => Self::member_name(bot, group_id, quoted.user_id).await,
+                    };
+                    let excerpt: String = cq_code
+                        .replace_all(&quoted.content, "")
+                        .chars()
+                        .take(20)
+                        .collect();
+                    tr.t_args(
+                        "archive.reply_to",
+                        &[("user_name", &user_name), ("excerpt", &excerpt)],
+                    )
                 }
+                None => tr.t("archive.reply_unknown"),
+            };
+            rendered.push_str(&quote);
+        }
+        rendered.push_str(&content[last..]);
+        Self::inline_media(&rendered)
+    }
+    // go-cqhttp may run on another machine than the bot, so the local copies
+    // are sent as base64, a file that is gone stays a file:// segment
+    fn inline_media(content: &str) -> String {
+        let re = Regex::new(r"\[CQ:(?P<kind>image|record),file=file://(?P<path>[^\]]+)\]").unwrap();
+        re.replace_all(content, |caps: &Captures| match fs::read(&caps["path"]) {
+            Ok(bytes) => format!(
+                "[CQ:{},file=base64://{}]",
+                &caps["kind"],
+                base64::encode(bytes)
             ),
-        )
-        .await?;
-        bot.api_request(
-            "send_group_msg",
-            json!({"group_id": group_id.unwrap(), "message": recalled_msg_content}),
-        )
-        .await?;
-        Ok(())
+            Err(err) => {
+                warn!("failed to read {}: {}", &caps["path"], err);
+                caps[0].to_string()
+            }
+        })
+        .to_string()
+    }
+    async fn fetch(url: &str) -> BotResult<Vec<u8>> {
+        let bytes = reqwest::Client::new()
+            .get(url)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(bytes.to_vec())
     }
-    async fn toggle(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
-        let re = Regex::new(r"^>archive\s+toggle\s*$").unwrap();
-        let msg = event.raw_message.unwrap();
-        if re.is_match(&msg) {
+    // runs at most once an hour
+    async fn prune(&self) -> BotResult<()> {
+        let now = SystemTime::now();
+        {
             let mut state = self.state.write().await;
-            state.is_enable = !state.is_enable;
-            if state.is_enable {
-                bot.api_request(
-                    "send_group_msg",
-                    json!({"group_id": event.group_id.unwrap(), "message": "撤回记录已开启"}),
+            let due = state.last_pruned.is_none_or(|last_pruned| {
+                now.duration_since(last_pruned).unwrap_or_default() >= Duration::from_secs(3600)
+            });
+            if !due {
+                return Ok(());
+            }
+            state.last_pruned = Some(now);
+        }
+        let retention = Duration::from_secs(self.config.retention_hours.max(0) as u64 * 3600);
+        let expired_at = Local::now().naive_local()
+            - chrono::Duration::hours(self.config.retention_hours.max(0));
+        sqlx::query!(r"DELETE FROM archive_message WHERE time < $1", expired_at)
+            .execute(&self.db)
+            .await?;
+        let entries = match fs::read_dir(&self.image_dir) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(()),
+        };
+        // the same file is shared by every message that carries it, so it
+        // stays while any of them is still cached
+        let re = Regex::new(r"\[CQ:(image|record),file=file://(?P<path>[^\]]+)\]").unwrap();
+        let referenced: HashSet<PathBuf> =
+            sqlx::query!(r"SELECT content FROM archive_message WHERE content LIKE '%file://%'")
+                .fetch_all(&self.db)
+                .await?
+                .iter()
+                .flat_map(|row| {
+                    re.captures_iter(&row.content)
+                        .map(|caps| PathBuf::from(&caps["path"]))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+        for entry in entries.flatten() {
+            let expired = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() > retention);
+            if expired && !referenced.contains(&entry.path()) {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+        Ok(())
+    }
+    async fn command(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let re = Regex::new(
+            r"^>archive\s+(?P<cmd>toggle|quiet|status|list|show|from)(\s+(?P<arg>.+?))?\s*$",
+        )
+        .unwrap();
+        let msg = match event.raw_message.as_ref() {
+            Some(msg) => msg,
+            None => return Ok(PluginFlow::Pass),
+        };
+        let (cmd, arg) = match re.captures(msg) {
+            Some(caps) => (
+                caps["cmd"].to_string(),
+                caps.name("arg").map(|arg| arg.as_str().to_string()),
+            ),
+            None => return Ok(PluginFlow::Pass),
+        };
+        if let "list" | "show" | "from" = cmd.as_str() {
+            self.history(&event, bot, &cmd, arg.as_deref()).await?;
+            return Ok(PluginFlow::Consumed);
+        }
+        let group_id = event.group_id.unwrap_or_default();
+        let tr = bot.tr(&event);
+        if let "toggle" | "quiet" = cmd.as_str() {
+            if !bot.is_admin(&event) {
+                return Err(BotError::UserInput(tr.t("archive.admin_only")));
+            }
+        }
+        let mode = self.mode(group_id).await;
+        let resp = match cmd.as_str() {
+            "toggle" if mode == ArchiveMode::Off => {
+                self.set_mode(group_id, ArchiveMode::On).await?;
+                tr.t("archive.enabled")
+            }
+            "toggle" => {
+                self.set_mode(group_id, ArchiveMode::Off).await?;
+                tr.t("archive.disabled")
+            }
+            "quiet" if mode == ArchiveMode::Quiet => {
+                self.set_mode(group_id, ArchiveMode::On).await?;
+                tr.t("archive.enabled")
+            }
+            "quiet" => {
+                self.set_mode(group_id, ArchiveMode::Quiet).await?;
+                tr.t("archive.quiet")
+            }
+            _ => {
+                let cached = sqlx::query!(
+                    r"SELECT COUNT(*) AS count FROM archive_message WHERE group_id = $1",
+                    group_id
                 )
-                .await?;
-            } else {
-                bot.api_request(
-                    "send_group_msg",
-                    json!({"group_id": event.group_id.unwrap(), "message": "撤回记录已关闭"}),
+                .fetch_one(&self.db)
+                .await?
+                .count;
+                let recalls = sqlx::query!(
+                    r"SELECT COUNT(*) AS count FROM archive_recall WHERE group_id = $1",
+                    group_id
+                )
+                .fetch_one(&self.db)
+                .await?
+                .count;
+                tr.t_args(
+                    "archive.status",
+                    &[
+                        ("mode", &tr.t(&format!("archive.mode_{}", mode.as_str()))),
+                        ("cached", &cached),
+                        ("recalls", &recalls),
+                        ("retention_hours", &self.config.retention_hours),
+                    ],
                 )
-                .await?;
             }
+        };
+        bot.reply(&event, resp).await?;
+        Ok(PluginFlow::Consumed)
+    }
+    // recorded recalls of the group as a forward message, for admins only
+    async fn history(
+        &self,
+        event: &CQEvent,
+        bot: &Bot,
+        cmd: &str,
+        arg: Option<&str>,
+    ) -> BotResult<()> {
+        let tr = bot.tr(event);
+        if !bot.is_admin(event) {
+            return Err(BotError::UserInput(tr.t("archive.admin_only")));
         }
-        Ok(())
+        let group_id = event.group_id.unwrap_or_default();
+        let recalls = match (cmd, arg) {
+            ("list", arg) => {
+                let limit = match arg {
+                    Some(arg) => arg
+                        .parse::<i64>()
+                        .ok()
+                        .filter(|limit| (1..=MAX_LISTED).contains(limit))
+                        .ok_or_else(|| {
+                            BotError::UserInput(
+                                tr.t_args("archive.bad_count", &[("max", &MAX_LISTED)]),
+                            )
+                        })?,
+                    None => DEFAULT_LISTED,
+                };
+                sqlx::query_as!(
+                    Recall,
+                    r#"SELECT id AS "id!", user_id AS "user_id!", operator_id AS "operator_id!",
+                    sent_at AS "sent_at!", recalled_at AS "recalled_at!", content AS "content!"
+                    FROM archive_recall WHERE group_id = $1
+                    ORDER BY id DESC LIMIT $2"#,
+                    group_id,
+                    limit
+                )
+                .fetch_all(&self.db)
+                .await?
+            }
+            ("show", Some(arg)) => {
+                let id = arg
+                    .trim_start_matches('#')
+                    .parse::<i64>()
+                    .map_err(|_| BotError::UserInput(tr.t("archive.bad_id")))?;
+                sqlx::query_as!(
+                    Recall,
+                    r#"SELECT id AS "id!", user_id AS "user_id!", operator_id AS "operator_id!",
+                    sent_at AS "sent_at!", recalled_at AS "recalled_at!", content AS "content!"
+                    FROM archive_recall WHERE group_id = $1 AND id = $2"#,
+                    group_id,
+                    id
+                )
+                .fetch_all(&self.db)
+                .await?
+            }
+            ("from", Some(arg)) => {
+                let re = Regex::new(r"^(\[CQ:at,qq=)?(?P<user_id>\d+)(,[^\]]*)?\]?$").unwrap();
+                let user_id = re
+                    .captures(arg)
+                    .and_then(|caps| caps["user_id"].parse::<i64>().ok())
+                    .ok_or_else(|| BotError::UserInput(tr.t("archive.bad_user")))?;
+                sqlx::query_as!(
+                    Recall,
+                    r#"SELECT id AS "id!", user_id AS "user_id!", operator_id AS "operator_id!",
+                    sent_at AS "sent_at!", recalled_at AS "recalled_at!", content AS "content!"
+                    FROM archive_recall WHERE group_id = $1 AND user_id = $2
+                    ORDER BY id DESC LIMIT $3"#,
+                    group_id,
+                    user_id,
+                    MAX_LISTED
+                )
+                .fetch_all(&self.db)
+                .await?
+            }
+            _ => return Err(BotError::UserInput(tr.t("archive.missing_arg"))),
+        };
+        if recalls.is_empty() {
+            bot.reply(event, tr.t("archive.no_recalls")).await?;
+            return Ok(());
+        }
+        let mut names: HashMap<i64, String> = HashMap::new();
+        let mut nodes = Vec::new();
+        for recall in recalls {
+            for user_id in [recall.user_id, recall.operator_id] {
+                if let Entry::Vacant(entry) = names.entry(user_id) {
+                    entry.insert(Self::member_name(bot, group_id, user_id).await);
+                }
+            }
+            let header = tr.t_args(
+                "archive.history_entry",
+                &[
+                    ("id", &recall.id),
+                    ("user_name", &names[&recall.user_id]),
+                    ("operator_name", &names[&recall.operator_id]),
+                    ("sent_at", &recall.sent_at),
+                    ("recalled_at", &recall.recalled_at),
+                ],
+            );
+            let content = self
+                .render_segments(&recall.content, group_id, bot, &tr)
+                .await;
+            nodes.push(format!("{header}\r\n{content}"));
+        }
+        bot.reply_forward(event, &tr.t("archive.history_title"), nodes)
+            .await
+    }
+    async fn member_name(bot: &Bot, group_id: i64, user_id: i64) -> String {
+        bot.member_name(group_id, user_id)
+            .await
+            .unwrap_or_else(|_| user_id.to_string())
     }
 }
 
+const DEFAULT_LISTED: i64 = 10;
+const MAX_LISTED: i64 = 20;
+
+struct Recall {
+    id: i64,
+    user_id: i64,
+    operator_id: i64,
+    sent_at: NaiveDateTime,
+    recalled_at: NaiveDateTime,
+    content: String,
+}
+
+// parameters of a CQ code segment like `[CQ:image,file=...,url=...]`
+fn cq_params(segment: &str) -> std::collections::HashMap<String, String> {
+    let unescape = Regex::new(r"&(amp|#91|#93|#44);").unwrap();
+    segment
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| {
+            let value = unescape.replace_all(value, |caps: &Captures| match &caps[1] {
+                "amp" => "&",
+                "#91" => "[",
+                "#93" => "]",
+                _ => ",",
+            });
+            (key.to_string(), value.to_string())
+        })
+        .collect()
+}
+
 #[async_trait::async_trait]
 impl Plugin for ArchivePlugin {
     fn name(&self) -> &'static str {
@@ -152,35 +776,59 @@ impl Plugin for ArchivePlugin {
         "自动复读已撤回的消息"
     }
     fn help(&self) -> &'static str {
-        "用法:\r\n>archive toggle 开启或关闭撤回记录"
+        "用法:\r\n>archive toggle 开启或关闭本群的撤回记录\r\n>archive quiet 只记录撤回，不再复读\r\n>archive status 查看本群的撤回记录状态\r\n>archive list [n] 查看最近n条撤回 (管理员)\r\n>archive show <id> 查看某条撤回 (管理员)\r\n>archive from @某人 查看某人的撤回 (管理员)"
     }
+    // private messages are only cached, for friend recalls
     fn senario(&self) -> PluginSenario {
-        PluginSenario::Group
+        PluginSenario::Both
     }
-    async fn handle(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
+    fn category(&self) -> &'static str {
+        "admin"
+    }
+    fn examples(&self) -> &'static [&'static str] {
+        &[">archive toggle", ">archive status", ">archive list 5"]
+    }
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
         match event.post_type.as_str() {
-            "notice" => match event.notice_type.as_ref().unwrap().as_str() {
+            "notice" => match event.notice_type.as_deref().unwrap_or_default() {
                 "group_recall" => self.archive(event, bot).await,
-                _ => Ok(()),
+                "friend_recall" => self.friend_archive(event, bot).await,
+                _ => Ok(PluginFlow::Pass),
             },
-            "message" => match event.message_type.as_ref().unwrap().as_str() {
-                "group" => self.toggle(event, bot).await,
-                _ => Ok(()),
-            },
-            _ => Ok(()),
+            "message" => {
+                // a broken cache must not hold back the plugins after this one
+                if let Err(err) = self.cache(&event).await {
+                    warn!("failed to cache message: {err}");
+                }
+                match event.group_id {
+                    Some(_) => self.command(event, bot).await,
+                    None => Ok(PluginFlow::Pass),
+                }
+            }
+            _ => Ok(PluginFlow::Pass),
         }
     }
 }
 
 #[derive(Deserialize)]
-struct DataExtractor {
-    data: InnerData,
+struct StrangerInfo {
+    nickname: String,
 }
 
 #[derive(Deserialize)]
-struct InnerData {
-    nickname: Option<String>,
-    card: Option<String>,
-    message: Option<String>,
-    time: Option<i32>,
+struct MsgInfo {
+    message: String,
+    time: i32,
+}
+
+// sees every group message before commands consume them, to cache it
+inventory::submit! {
+    PluginFactory::new("archive", |config| {
+        Box::pin(async move {
+            Ok(vec![
+                Box::new(ArchivePlugin::new(plugin_config(config)?).await?) as BoxedPlugin
+            ])
+        })
+    })
+    .with_priority(100)
 }
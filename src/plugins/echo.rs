@@ -1,9 +1,10 @@
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
 
 use crate::bot::Bot;
-use crate::models::{CQEvent, Plugin, PluginSenario};
+use crate::error::BotResult;
+use crate::models::{CQEvent, Plugin, PluginFlow, PluginSenario};
+use crate::plugins::{plugin_config, BoxedPlugin, PluginFactory};
 
 #[derive(Serialize, Deserialize)]
 pub struct EchoPluginConfig;
@@ -18,23 +19,18 @@ impl EchoPlugin {
             _config: config.unwrap_or(EchoPluginConfig),
         }
     }
-    async fn echo(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
-        let msg = event.raw_message.as_ref().unwrap();
-        let group_id = event.group_id.unwrap();
+    async fn echo(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let msg = match event.raw_message.as_ref() {
+            Some(msg) => msg,
+            None => return Ok(PluginFlow::Pass),
+        };
         let re = Regex::new(r"^>echo\s+(?P<content>.+)$").unwrap();
         if !re.is_match(msg) {
-            return Ok(());
+            return Ok(PluginFlow::Pass);
         }
-        let content = re.replace_all(&msg, "$content").to_string();
-        bot.api_request(
-            "send_group_msg",
-            &Req {
-                group_id,
-                message: content,
-            },
-        )
-        .await?;
-        Ok(())
+        let content = re.replace_all(msg, "$content").to_string();
+        bot.reply(&event, content).await?;
+        Ok(PluginFlow::Consumed)
     }
 }
 
@@ -50,18 +46,26 @@ impl Plugin for EchoPlugin {
         "用法:\r\n>echo <复读内容>"
     }
     fn senario(&self) -> PluginSenario {
-        PluginSenario::Group
+        PluginSenario::Both
     }
-    async fn handle(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
+    fn category(&self) -> &'static str {
+        "fun"
+    }
+    fn examples(&self) -> &'static [&'static str] {
+        &[">echo 你好"]
+    }
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
         match event.post_type.as_str() {
             "message" => self.echo(event, bot).await,
-            _ => Ok(()),
+            _ => Ok(PluginFlow::Pass),
         }
     }
 }
 
-#[derive(Serialize)]
-struct Req {
-    group_id: i64,
-    message: String,
+inventory::submit! {
+    PluginFactory::new("echo", |config| {
+        Box::pin(async move {
+            Ok(vec![Box::new(EchoPlugin::new(plugin_config(config)?)) as BoxedPlugin])
+        })
+    })
 }
@@ -1,100 +1,65 @@
-use log::debug;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::error::Error;
-use tokio::sync::RwLock;
 
 use crate::bot::Bot;
-use crate::models::{CQEvent, Plugin, PluginSenario};
-
-#[derive(Default, Deserialize, Serialize)]
-struct HOKpPluginState {
-    // ignored_cnt: Arc<Mutex<usize>>,
-    last_msg_timestamp: i64,
-}
+use crate::error::{BotError, BotResult};
+use crate::models::{CQEvent, Plugin, PluginFlow, PluginSenario};
+use crate::plugins::{plugin_config, BoxedPlugin, PluginFactory, PASSIVE_PRIORITY};
 
 #[derive(Default, Deserialize, Serialize)]
 pub struct HOKpPluginConfig {
     pub not_hokp_patterns: Vec<String>,
     pub hokp_patterns: Vec<String>,
-    pub whitelist: Vec<i64>,
-    pub sleep_seconds: i64,
 }
 
 pub struct HOKpPlugin {
-    state: RwLock<HOKpPluginState>,
     config: HOKpPluginConfig,
 }
 
 impl HOKpPlugin {
     pub fn new(config: Option<HOKpPluginConfig>) -> Self {
         HOKpPlugin {
-            state: RwLock::new(HOKpPluginState {
-                last_msg_timestamp: 0,
-            }),
             config: config.unwrap_or_default(),
         }
     }
-    async fn hokp(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
-        let msg = event.raw_message.unwrap();
+    async fn hokp(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let msg = match event.raw_message.as_ref() {
+            Some(msg) => msg,
+            None => return Ok(PluginFlow::Pass),
+        };
         let mut not_hokp = false;
         for pattern in self.config.not_hokp_patterns.iter() {
-            let re = Regex::new(pattern).unwrap();
-            if re.is_match(&msg) {
+            let re = Regex::new(pattern).map_err(|err| BotError::Config(err.to_string()))?;
+            if re.is_match(msg) {
                 not_hokp = true;
                 break;
             }
         }
         if !not_hokp {
-            return Ok(());
+            return Ok(PluginFlow::Pass);
         }
-        bot.api_request(
-            "send_group_msg",
-            json!({
-                "group_id": event.group_id.unwrap(),
-                "message": "要不咱玩农吧"
-            }),
-        )
-        .await?;
-        let mut state = self.state.write().await;
-        let now_timestamp = chrono::Utc::now().timestamp();
-        state.last_msg_timestamp = now_timestamp;
-        Ok(())
+        bot.reply(&event, bot.tr(&event).t("hokp.not_hokp")).await?;
+        Ok(PluginFlow::Consumed)
     }
 
-    async fn anti_hokp(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
-        let msg = event.raw_message.unwrap();
+    async fn anti_hokp(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let msg = match event.raw_message.as_ref() {
+            Some(msg) => msg,
+            None => return Ok(PluginFlow::Pass),
+        };
         let mut is_hokp = false;
         for pattern in self.config.hokp_patterns.iter() {
-            let re = Regex::new(pattern).unwrap();
-            if re.is_match(&msg) {
+            let re = Regex::new(pattern).map_err(|err| BotError::Config(err.to_string()))?;
+            if re.is_match(msg) {
                 is_hokp = true;
                 break;
             }
         }
         if !is_hokp {
-            return Ok(());
+            return Ok(PluginFlow::Pass);
         }
-        bot.api_request(
-            "send_group_msg",
-            json!({
-                "group_id": event.group_id.unwrap(),
-                "message": "农批收收味"
-            }),
-        )
-        .await?;
-        let mut state = self.state.write().await;
-        let now_timestamp = chrono::Utc::now().timestamp();
-        state.last_msg_timestamp = now_timestamp;
-        Ok(())
-    }
-    fn filter(&self, group_id: i64) -> bool {
-        self.config
-            .whitelist
-            .iter()
-            .find(|&&x| x == group_id)
-            .is_some()
+        bot.reply(&event, bot.tr(&event).t("hokp.hokp")).await?;
+        Ok(PluginFlow::Consumed)
     }
 }
 
@@ -109,41 +74,37 @@ impl Plugin for HOKpPlugin {
     }
 
     fn help(&self) -> &'static str {
-        ""
+        "消息匹配配置中的关键词时自动回应"
     }
 
     fn senario(&self) -> PluginSenario {
         PluginSenario::Group
     }
 
-    async fn handle(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
+    fn category(&self) -> &'static str {
+        "fun"
+    }
+
+    fn is_passive(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
         match event.post_type.as_str() {
-            "message" => match event.message_type.as_ref().unwrap().as_str() {
-                "group" => {
-                    let group_id = event.group_id.clone().unwrap();
-                    if !self.filter(group_id) {
-                        debug!("group_id is not in white list. returning...");
-                        return Ok(());
-                    }
-                    {
-                        let now_timestamp = chrono::Utc::now().timestamp();
-                        let state = self.state.read().await;
-                        if now_timestamp - state.last_msg_timestamp < self.config.sleep_seconds {
-                            debug!(
-                                "plugin sleeping. {} seconds remaining. returning...",
-                                self.config.sleep_seconds + state.last_msg_timestamp
-                                    - now_timestamp
-                            );
-                            return Ok(());
-                        }
-                    }
-                    self.hokp(event.clone(), bot).await?;
-                    self.anti_hokp(event, bot).await
-                }
-                "private" => Ok(()),
-                _ => unreachable!(),
+            "message" => match self.hokp(event.clone(), bot).await? {
+                PluginFlow::Consumed => Ok(PluginFlow::Consumed),
+                PluginFlow::Pass => self.anti_hokp(event, bot).await,
             },
-            _ => Ok(()),
+            _ => Ok(PluginFlow::Pass),
         }
     }
 }
+
+inventory::submit! {
+    PluginFactory::new("hokp", |config| {
+        Box::pin(async move {
+            Ok(vec![Box::new(HOKpPlugin::new(plugin_config(config)?)) as BoxedPlugin])
+        })
+    })
+    .with_priority(PASSIVE_PRIORITY)
+}
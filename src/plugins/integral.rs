@@ -1,23 +1,104 @@
-use std::error::Error;
+use std::collections::HashMap;
 
-use chrono::{Duration, Local, NaiveDateTime};
+use chrono::{Duration, Local, NaiveDateTime, NaiveTime};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+use sqlx::SqlitePool;
+use unicode_width::UnicodeWidthStr;
 
 use crate::{
     bot::Bot,
-    models::{CQEvent, Plugin, PluginSenario},
+    db,
+    error::{BotError, BotResult},
+    i18n::Translator,
+    models::{CQEvent, Plugin, PluginFlow, PluginSenario},
+    plugins::{plugin_config, BoxedPlugin, PluginFactory},
+    render::TableImage,
 };
 
 struct IntegralPluginState {
     db: SqlitePool,
+    deadline: Option<NaiveTime>,
+    table_image: Option<TableImage>,
 }
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct IntegralPluginConfig {
     db_url: String,
+    // a streak ends when nobody punches for this long
+    #[serde(default = "default_window_hours")]
+    window_hours: i64,
+    // "HH:MM" in local time, when set a punch is due once per day and the
+    // day changes at this time, replacing `window_hours`
+    #[serde(default)]
+    deadline: Option<String>,
+    // missed days a single streak survives
+    #[serde(default)]
+    freeze_tokens: i64,
+    // announced when a punch crosses them, the badge is kept for good
+    #[serde(default = "default_milestones")]
+    milestones: Vec<Milestone>,
+    #[serde(default = "default_ranking_page_size")]
+    ranking_page_size: usize,
+    // entries above and below the sender in `>integral ranking me`
+    #[serde(default = "default_around_me")]
+    around_me: usize,
+    // a ttf or otf font, when set rankings are sent as images drawn with it,
+    // it needs CJK glyphs to draw chinese names
+    #[serde(default)]
+    ranking_font: Option<String>,
+    // the group that gets the time cards from before they were kept per group
+    #[serde(default)]
+    legacy_group_id: Option<i64>,
+}
+
+fn default_ranking_page_size() -> usize {
+    10
+}
+
+fn default_around_me() -> usize {
+    3
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Milestone {
+    days: i64,
+    badge: String,
+    // replaces the default announcement, `{user_name}`, `{days}` and
+    // `{badge}` are filled in
+    #[serde(default)]
+    message: Option<String>,
+}
+
+fn default_milestones() -> Vec<Milestone> {
+    [(7, "🌱"), (30, "🌳"), (100, "🏆")]
+        .into_iter()
+        .map(|(days, badge)| Milestone {
+            days,
+            badge: badge.to_string(),
+            message: None,
+        })
+        .collect()
+}
+
+fn default_window_hours() -> i64 {
+    24
+}
+
+impl Default for IntegralPluginConfig {
+    fn default() -> Self {
+        IntegralPluginConfig {
+            db_url: "sqlite://integral.db".to_string(),
+            window_hours: default_window_hours(),
+            deadline: None,
+            freeze_tokens: 0,
+            milestones: default_milestones(),
+            ranking_page_size: default_ranking_page_size(),
+            around_me: default_around_me(),
+            ranking_font: None,
+            legacy_group_id: None,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -42,12 +123,15 @@ impl Plugin for IntegralPlugin {
             ">integral <cmd>\r\n",
             "\r\n",
             "cmd列表:\r\n",
+            "\tbest\t\t查看最长记录\r\n",
             "\tderivative\t破戒\r\n",
-            "\tpunch\t\t打卡\r\n",
-            "\tranking\t查看群内排名\r\n",
+            "\thistory\t查看最近的记录\r\n",
+            "\tpunch\t\t打卡，第一次打卡即加入本群排名\r\n",
+            "\tranking [best] [页码|me]\t查看群内排名，best为历史最长排名，me为自己附近的排名\r\n",
+            "\tstats\t\t查看统计\r\n",
             "\tstatus\t\t查看状态\r\n",
             "\r\n",
-            "24小时内未打卡会导致计时清零"
+            "未按时打卡会导致计时清零，规则见status"
         )
     }
 
@@ -55,154 +139,467 @@ impl Plugin for IntegralPlugin {
         PluginSenario::Group
     }
 
-    async fn handle(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
+    fn category(&self) -> &'static str {
+        "fun"
+    }
+
+    fn examples(&self) -> &'static [&'static str] {
+        &[
+            ">integral punch",
+            ">integral ranking",
+            ">integral ranking 2",
+            ">integral ranking me",
+            ">integral ranking best",
+        ]
+    }
+
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
         match event.post_type.as_str() {
-            "message" => match event.message_type.as_ref().unwrap().as_str() {
-                "group" => self.integral(event, bot).await,
-                "private" => Ok(()),
-                _ => unreachable!(),
-            },
-            _ => Ok(()),
+            "message" => self.integral(event, bot).await,
+            _ => Ok(PluginFlow::Pass),
         }
     }
 }
 
 impl IntegralPlugin {
-    pub async fn new(config: Option<IntegralPluginConfig>) -> Self {
+    pub async fn new(config: Option<IntegralPluginConfig>) -> BotResult<Self> {
         let config = config.unwrap_or_default();
+        if config.db_url.is_empty() {
+            return Err(BotError::Config("db_url must not be empty".to_string()));
+        }
+        if config.window_hours <= 0 {
+            return Err(BotError::Config(
+                "window_hours must be positive".to_string(),
+            ));
+        }
+        if config.freeze_tokens < 0 {
+            return Err(BotError::Config(
+                "freeze_tokens must not be negative".to_string(),
+            ));
+        }
+        if config
+            .milestones
+            .iter()
+            .any(|milestone| milestone.days <= 0)
+        {
+            return Err(BotError::Config(
+                "milestone days must be positive".to_string(),
+            ));
+        }
+        if config.ranking_page_size == 0 {
+            return Err(BotError::Config(
+                "ranking_page_size must be positive".to_string(),
+            ));
+        }
+        let deadline = match &config.deadline {
+            Some(deadline) => Some(
+                NaiveTime::parse_from_str(deadline, "%H:%M")
+                    .map_err(|err| BotError::Config(format!("deadline {deadline}: {err}")))?,
+            ),
+            None => None,
+        };
+        let table_image = match &config.ranking_font {
+            Some(path) => Some(TableImage::load(path)?),
+            None => None,
+        };
+        let db = db::connect(&config.db_url).await?;
+        if let Some(group_id) = config.legacy_group_id {
+            sqlx::query!(
+                r"UPDATE integral_time_card SET group_id = $1 WHERE group_id = 0",
+                group_id
+            )
+            .execute(&db)
+            .await?;
+        }
         let state = IntegralPluginState {
-            db: SqlitePoolOptions::new()
-                .connect(&config.db_url)
-                .await
-                .expect("database connection failed"),
+            db,
+            deadline,
+            table_image,
         };
-        Self { state, config }
+        Ok(Self { state, config })
     }
-    async fn integral(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
-        let cmd = Self::resolve(event.raw_message.unwrap());
-        if let None = cmd {
-            return Ok(());
+    async fn integral(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let cmd = match event.raw_message.as_deref().and_then(Self::resolve) {
+            Some(cmd) => cmd,
+            None => return Ok(PluginFlow::Pass),
+        };
+        let (group_id, user_id) = match (event.group_id, event.user_id) {
+            (Some(group_id), Some(user_id)) => (group_id, user_id),
+            _ => return Ok(PluginFlow::Pass),
+        };
+        let tr = bot.tr(&event);
+        if let Cmd::Ranking(query) = cmd {
+            return self
+                .send_ranking(&event, bot, group_id, user_id, query)
+                .await;
+        }
+        let joined = self.get_card_db(group_id, user_id).await?.is_some();
+        if !joined && !matches!(cmd, Cmd::Punch) {
+            bot.reply(&event, tr.t("integral.not_joined")).await?;
+            return Ok(PluginFlow::Consumed);
         }
-        let cmd = cmd.unwrap();
-        let user_id = event.user_id.unwrap();
-        let group_id = event.group_id.unwrap();
         if let Cmd::Derivative = cmd {
-            self.derivative(user_id).await;
-            bot.api_request(
-                "send_group_msg",
-                json!({
-                    "group_id": group_id,
-                    "message": "不准导！积回去！"
-                }),
-            )
-            .await?;
-            return Ok(());
-        }
-        if let Cmd::Ranking = cmd {
-            let list = self.ranking(group_id, bot).await?;
-            let mut msg = String::new();
-            let mut previous = Duration::zero();
-            let mut ranking = 1;
-            for (index, entry) in list.iter().enumerate() {
-                let user_info = bot
-                    .api_request(
-                        "get_group_member_info",
-                        json!(
-                            {
-                                "group_id": group_id,
-                                "user_id": entry.user_id,
-                            }
-                        ),
-                    )
-                    .await?
-                    .json::<UserDataExtractor>()
-                    .await
-                    .unwrap()
-                    .data;
-                let user_name = match user_info.card {
-                    name if name.as_str() != "" => name,
-                    _ => user_info.nickname,
-                };
-                if entry.score != previous {
-                    ranking = 1 + index;
-                    previous = entry.score;
+            self.derivative(group_id, user_id).await?;
+            bot.reply(&event, tr.t("integral.derivative")).await?;
+            return Ok(PluginFlow::Consumed);
+        }
+        match cmd {
+            Cmd::Best => return self.best(&event, bot, group_id, user_id).await,
+            Cmd::History => return self.history(&event, bot, group_id, user_id).await,
+            Cmd::Stats => return self.stats(&event, bot, group_id, user_id).await,
+            _ => (),
+        }
+        let mut notes = Vec::new();
+        let mut crossed = Vec::new();
+        let res = match cmd {
+            Cmd::Punch if !joined => {
+                self.add_user_db(group_id, user_id).await?;
+                Duration::zero()
+            }
+            Cmd::Punch => {
+                let punch = self.punch(group_id, user_id).await?;
+                if punch.freezes > 0 {
+                    notes.push(tr.t_args("integral.frozen", &[("count", &punch.freezes)]));
                 }
-                msg.push_str(
-                    format!(
-                        "{:5}. {:20}\r\n\t\t\t{:>15}\r\n\r\n",
-                        ranking,
-                        user_name,
-                        Self::duration_to_string(entry.score)
-                    )
-                    .as_str(),
-                )
+                crossed = self.crossed(&punch);
+                punch.streak
             }
-            bot.api_request(
-                "send_group_msg",
-                json!({
-                    "group_id": group_id,
-                    "message": msg,
-                }),
-            )
-            .await?;
-            return Ok(());
+            Cmd::Status => self.status(group_id, user_id).await?,
+            _ => unreachable!(),
+        };
+        let user_name = bot.member_name(group_id, user_id).await?;
+        let punched = match cmd {
+            Cmd::Punch if !joined => tr.t("integral.joined"),
+            Cmd::Punch => tr.t("integral.punched"),
+            _ => String::new(),
+        };
+        let mut msg = tr.t_args(
+            "integral.status",
+            &[
+                ("user_name", &user_name),
+                ("punched", &punched),
+                ("duration", &Self::duration_to_string(res)),
+            ],
+        );
+        if let Cmd::Status = cmd {
+            let badges = self.get_badges_db(group_id, user_id).await?;
+            if !badges.is_empty() {
+                notes.push(tr.t_args("integral.badges", &[("badges", &badges.join(" "))]));
+            }
+            notes.push(self.rules(&tr, &self.card(group_id, user_id).await?));
         }
-        let res = match cmd {
-            Cmd::Punch => self.punch(user_id).await,
-            Cmd::Status => self.status(user_id).await,
-            Cmd::Derivative => unreachable!(),
-            Cmd::Ranking => unreachable!(),
+        for note in notes {
+            msg.push_str("\r\n");
+            msg.push_str(&note);
+        }
+        bot.reply(&event, msg).await?;
+        for milestone in crossed {
+            // a badge is announced once, even when a later streak crosses it again
+            if !self
+                .add_badge_db(group_id, user_id, &milestone.badge)
+                .await?
+            {
+                continue;
+            }
+            let template = match &milestone.message {
+                Some(message) => message.clone(),
+                None => tr.t("integral.milestone"),
+            };
+            let announcement = template
+                .replace("{user_name}", &user_name)
+                .replace("{days}", &milestone.days.to_string())
+                .replace("{badge}", &milestone.badge);
+            bot.reply(&event, announcement).await?;
+        }
+        Ok(PluginFlow::Consumed)
+    }
+    async fn send_ranking(
+        &self,
+        event: &CQEvent,
+        bot: &Bot,
+        group_id: i64,
+        user_id: i64,
+        query: RankingQuery,
+    ) -> BotResult<PluginFlow> {
+        let tr = bot.tr(event);
+        let list = match query.best {
+            true => self.best_ranking(group_id).await?,
+            false => self.ranking(group_id).await?,
         };
-        let user_info = bot
-            .api_request(
-                "get_group_member_info",
-                json!(
-                    {
-                        "group_id": group_id,
-                        "user_id": user_id,
-                    }
+        if list.is_empty() {
+            bot.reply(event, tr.t("integral.no_participants")).await?;
+            return Ok(PluginFlow::Consumed);
+        }
+        // entries with the same score share a rank
+        let mut ranks: Vec<usize> = Vec::with_capacity(list.len());
+        for (index, entry) in list.iter().enumerate() {
+            let rank = match index {
+                0 => 1,
+                _ if entry.score == list[index - 1].score => ranks[index - 1],
+                _ => index + 1,
+            };
+            ranks.push(rank);
+        }
+        let page_size = self.config.ranking_page_size;
+        let pages = list.len().div_ceil(page_size);
+        let (range, subtitle) = match query.view {
+            RankingView::Page(page) if page > pages => {
+                let msg = tr.t_args("integral.no_page", &[("pages", &pages)]);
+                bot.reply(event, msg).await?;
+                return Ok(PluginFlow::Consumed);
+            }
+            RankingView::Page(page) => (
+                (page - 1) * page_size..(page * page_size).min(list.len()),
+                tr.t_args("integral.page", &[("page", &page), ("pages", &pages)]),
+            ),
+            RankingView::AroundMe => match list.iter().position(|entry| entry.user_id == user_id) {
+                Some(index) => (
+                    index.saturating_sub(self.config.around_me)
+                        ..(index + self.config.around_me + 1).min(list.len()),
+                    tr.t("integral.around_me"),
                 ),
-            )
+                None => {
+                    bot.reply(event, tr.t("integral.not_joined")).await?;
+                    return Ok(PluginFlow::Consumed);
+                }
+            },
+        };
+        let title = match query.best {
+            true => tr.t("integral.best_ranking_title"),
+            false => tr.t("integral.ranking_title"),
+        };
+        let title = format!("{title} {subtitle}");
+        let badges = self.group_badges(group_id).await?;
+        let mut rows = Vec::new();
+        for index in range {
+            let entry = &list[index];
+            let mut user_name = bot.member_name(group_id, entry.user_id).await?;
+            if let Some(badges) = badges.get(&entry.user_id) {
+                user_name = format!("{user_name} {}", badges.join(""));
+            }
+            rows.push(vec![
+                format!("{}.", ranks[index]),
+                user_name,
+                Self::duration_to_string(entry.score),
+            ]);
+        }
+        let msg = match &self.state.table_image {
+            Some(table_image) => format!(
+                "[CQ:image,file=base64://{}]",
+                base64::encode(table_image.render(&title, &rows)?)
+            ),
+            None => Self::text_table(&title, &rows),
+        };
+        bot.reply(event, msg).await?;
+        Ok(PluginFlow::Consumed)
+    }
+    // pads every column but the last to its widest cell, counting CJK
+    // characters as two columns
+    fn text_table(title: &str, rows: &[Vec<String>]) -> String {
+        let mut widths: Vec<usize> = Vec::new();
+        for row in rows {
+            for (column, cell) in row.iter().enumerate() {
+                match widths.get_mut(column) {
+                    Some(width) => *width = (*width).max(cell.width()),
+                    None => widths.push(cell.width()),
+                }
+            }
+        }
+        let mut lines = vec![title.to_string()];
+        for row in rows {
+            let mut line = String::new();
+            for (column, cell) in row.iter().enumerate() {
+                line.push_str(cell);
+                if column + 1 < row.len() {
+                    line.push_str(&" ".repeat(widths[column] - cell.width() + 2));
+                }
+            }
+            lines.push(line);
+        }
+        lines.join("\r\n")
+    }
+    // milestones the streak passed between the previous punch and this one
+    fn crossed(&self, punch: &Punch) -> Vec<&Milestone> {
+        self.config
+            .milestones
+            .iter()
+            .filter(|milestone| {
+                let duration = Duration::days(milestone.days);
+                punch.previous < duration && duration <= punch.streak
+            })
+            .collect()
+    }
+    async fn group_badges(&self, group_id: i64) -> BotResult<HashMap<i64, Vec<String>>> {
+        let mut ret: HashMap<i64, Vec<String>> = HashMap::new();
+        for badge in self.get_group_badges_db(group_id).await? {
+            ret.entry(badge.user_id).or_default().push(badge.badge);
+        }
+        Ok(ret)
+    }
+    fn rules(&self, tr: &Translator<'_>, card: &TimeCard) -> String {
+        let mut rules = match self.state.deadline {
+            Some(deadline) => tr.t_args(
+                "integral.rules_deadline",
+                &[("deadline", &deadline.format("%H:%M"))],
+            ),
+            None => tr.t_args(
+                "integral.rules_window",
+                &[("hours", &self.config.window_hours)],
+            ),
+        };
+        if self.config.freeze_tokens > 0 {
+            let left = self.config.freeze_tokens - card.freezes_used;
+            rules.push_str("\r\n");
+            rules.push_str(&tr.t_args(
+                "integral.rules_freeze",
+                &[("left", &left), ("total", &self.config.freeze_tokens)],
+            ));
+        }
+        if card.started_at < card.updated_at {
+            // a frozen streak is due at the end of the day the freeze covers
+            let now = Local::now().naive_local();
+            let due = self.expires_at(card.updated_at)
+                + self.period() * self.freezes_needed(card, now) as i32;
+            rules.push_str("\r\n");
+            rules
+                .push_str(&tr.t_args("integral.rules_due", &[("due", &due.format("%m-%d %H:%M"))]));
+        }
+        rules
+    }
+    async fn best(
+        &self,
+        event: &CQEvent,
+        bot: &Bot,
+        group_id: i64,
+        user_id: i64,
+    ) -> BotResult<PluginFlow> {
+        let now = Local::now().naive_local();
+        let current = self.unrecorded(&self.card(group_id, user_id).await?, now);
+        let best = self
+            .get_streaks_db(group_id, user_id)
             .await?
-            .json::<UserDataExtractor>()
-            .await
-            .unwrap()
-            .data;
-        let user_name = match user_info.card {
-            name if name.as_str() != "" => name,
-            _ => user_info.nickname,
+            .iter()
+            .map(Streak::duration)
+            .fold(current, Duration::max);
+        let user_name = bot.member_name(group_id, user_id).await?;
+        let msg = bot.tr(event).t_args(
+            "integral.best",
+            &[
+                ("user_name", &user_name),
+                ("duration", &Self::duration_to_string(best)),
+            ],
+        );
+        bot.reply(event, msg).await?;
+        Ok(PluginFlow::Consumed)
+    }
+    async fn history(
+        &self,
+        event: &CQEvent,
+        bot: &Bot,
+        group_id: i64,
+        user_id: i64,
+    ) -> BotResult<PluginFlow> {
+        let tr = bot.tr(event);
+        let streaks = self.get_streaks_db(group_id, user_id).await?;
+        if streaks.is_empty() {
+            bot.reply(event, tr.t("integral.no_history")).await?;
+            return Ok(PluginFlow::Consumed);
+        }
+        let msg = streaks
+            .iter()
+            .take(HISTORY_LISTED)
+            .map(|streak| {
+                tr.t_args(
+                    "integral.history_entry",
+                    &[
+                        ("started_at", &streak.started_at.format("%m-%d %H:%M")),
+                        ("ended_at", &streak.ended_at.format("%m-%d %H:%M")),
+                        ("duration", &Self::duration_to_string(streak.duration())),
+                        ("reason", &tr.t(&format!("integral.{}", streak.reason))),
+                    ],
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        bot.reply(event, msg).await?;
+        Ok(PluginFlow::Consumed)
+    }
+    async fn stats(
+        &self,
+        event: &CQEvent,
+        bot: &Bot,
+        group_id: i64,
+        user_id: i64,
+    ) -> BotResult<PluginFlow> {
+        let tr = bot.tr(event);
+        let streaks = self.get_streaks_db(group_id, user_id).await?;
+        let average = match streaks.len() {
+            0 => Duration::zero(),
+            count => {
+                let total = streaks
+                    .iter()
+                    .map(Streak::duration)
+                    .fold(Duration::zero(), |a, b| a + b);
+                total / count as i32
+            }
         };
-        let mut resp = String::new();
-        if let Cmd::Punch = cmd {
-            resp = "打卡成功。".to_string();
-        }
-        let msg = format!(
-            "{} {}已戒导 {}",
-            user_name,
-            resp,
-            Self::duration_to_string(res)
+        let relapses = streaks
+            .iter()
+            .filter(|streak| streak.reason == StreakEnd::Relapse.as_str())
+            .count();
+        let user_name = bot.member_name(group_id, user_id).await?;
+        let mut msg = tr.t_args(
+            "integral.stats",
+            &[
+                ("user_name", &user_name),
+                ("count", &streaks.len()),
+                ("average", &Self::duration_to_string(average)),
+                ("relapses", &relapses),
+            ],
         );
-        bot.api_request(
-            "send_group_msg",
-            json!({
-                "group_id": group_id,
-                "message": msg
-            }),
-        )
-        .await?;
-        Ok(())
-    }
-    fn resolve(msg: String) -> Option<Cmd> {
-        let re = Regex::new(r"^>integral\s+(?P<cmd>\S+)\s*$").unwrap();
-        let cmd = re.replace_all(&msg, "$cmd").to_string();
-        match cmd.as_str() {
-            "punch" => Some(Cmd::Punch),
-            "status" => Some(Cmd::Status),
-            "derivative" => Some(Cmd::Derivative),
-            "ranking" => Some(Cmd::Ranking),
+        if let Some(longest) = self.best_ranking(group_id).await?.first() {
+            let name = bot.member_name(group_id, longest.user_id).await?;
+            msg.push_str("\r\n");
+            msg.push_str(&tr.t_args(
+                "integral.longest",
+                &[
+                    ("user_name", &name),
+                    ("duration", &Self::duration_to_string(longest.score)),
+                ],
+            ));
+        }
+        bot.reply(event, msg).await?;
+        Ok(PluginFlow::Consumed)
+    }
+    fn resolve(msg: &str) -> Option<Cmd> {
+        let re = Regex::new(r"^>integral\s+(?P<cmd>\S+)(?P<args>(\s+\S+)*)\s*$").unwrap();
+        let caps = re.captures(msg)?;
+        let args: Vec<&str> = caps["args"].split_whitespace().collect();
+        match (&caps["cmd"], args.as_slice()) {
+            ("punch", []) => Some(Cmd::Punch),
+            ("status", []) => Some(Cmd::Status),
+            ("derivative", []) => Some(Cmd::Derivative),
+            ("ranking", args) => Self::resolve_ranking(args).map(Cmd::Ranking),
+            ("best", []) => Some(Cmd::Best),
+            ("history", []) => Some(Cmd::History),
+            ("stats", []) => Some(Cmd::Stats),
             _ => None,
         }
     }
+    // `ranking [best] [<page>|me]`
+    fn resolve_ranking(args: &[&str]) -> Option<RankingQuery> {
+        let (best, args) = match args {
+            ["best", rest @ ..] => (true, rest),
+            _ => (false, args),
+        };
+        let view = match args {
+            [] => RankingView::Page(1),
+            ["me"] => RankingView::AroundMe,
+            [page] => RankingView::Page(page.parse().ok().filter(|page| *page > 0)?),
+            _ => return None,
+        };
+        Some(RankingQuery { best, view })
+    }
     fn duration_to_string(dur: Duration) -> String {
         let weeks = dur.num_weeks();
         let days = dur.num_days() - 7 * dur.num_weeks();
@@ -210,12 +607,13 @@ impl IntegralPlugin {
         let minutes = dur.num_minutes() - 60 * dur.num_hours();
         let seconds = dur.num_seconds() - 60 * dur.num_minutes();
 
-        let mut map = Vec::new();
-        map.push(("w", weeks));
-        map.push(("d", days));
-        map.push(("h", hours));
-        map.push(("m", minutes));
-        map.push(("s", seconds));
+        let map = vec![
+            ("w", weeks),
+            ("d", days),
+            ("h", hours),
+            ("m", minutes),
+            ("s", seconds),
+        ];
 
         let mut ret = String::new();
         for (k, v) in map {
@@ -226,150 +624,498 @@ impl IntegralPlugin {
         }
         ret
     }
-    async fn punch(&self, user_id: i64) -> Duration {
-        let res = self.status(user_id).await;
-        self.update_updated_at_db(user_id).await.ok();
-        res
+    // one missed punch, what a freeze token covers
+    fn period(&self) -> Duration {
+        match self.state.deadline {
+            Some(_) => Duration::days(1),
+            None => Duration::hours(self.config.window_hours),
+        }
     }
-    async fn status(&self, user_id: i64) -> Duration {
-        let now = Local::now().naive_local();
-        match self.get_updated_at_db(user_id).await {
-            Ok(res) => {
-                if now - res >= Duration::days(1) {
-                    self.update_started_at_db(user_id).await.ok();
-                    Duration::zero()
-                } else {
-                    let start_time = self.get_started_at_db(user_id).await.unwrap();
-                    now - start_time
+    // when the next punch after one at `punched_at` is due
+    fn expires_at(&self, punched_at: NaiveDateTime) -> NaiveDateTime {
+        match self.state.deadline {
+            Some(deadline) => {
+                let mut day_end = punched_at.date().and_time(deadline);
+                if day_end <= punched_at {
+                    day_end += Duration::days(1);
                 }
+                day_end + Duration::days(1)
             }
-            Err(_) => {
-                self.add_user_db(user_id).await.ok();
-                Duration::zero()
+            None => punched_at + self.period(),
+        }
+    }
+    // freeze tokens the streak needs to still be alive now
+    fn freezes_needed(&self, card: &TimeCard, now: NaiveDateTime) -> i64 {
+        let expires_at = self.expires_at(card.updated_at);
+        if now < expires_at {
+            return 0;
+        }
+        (now - expires_at).num_seconds() / self.period().num_seconds() + 1
+    }
+    // the current streak, zero once a punch was missed and no freeze token
+    // is left to cover it
+    fn score(&self, card: &TimeCard, now: NaiveDateTime) -> Duration {
+        let freezes_left = self.config.freeze_tokens - card.freezes_used;
+        if self.freezes_needed(card, now) > freezes_left {
+            Duration::zero()
+        } else {
+            now - card.started_at
+        }
+    }
+    // the streak of a card that integral_streak doesn't have yet, the current
+    // one or one that expired before a punch or query could close it
+    fn unrecorded(&self, card: &TimeCard, now: NaiveDateTime) -> Duration {
+        match self.score(card, now) {
+            score if score.is_zero() && card.started_at < card.updated_at => {
+                card.updated_at - card.started_at
+            }
+            score => score,
+        }
+    }
+    async fn punch(&self, group_id: i64, user_id: i64) -> BotResult<Punch> {
+        let now = Local::now().naive_local();
+        let card = self.card(group_id, user_id).await?;
+        let freezes = self.freezes_needed(&card, now);
+        let streak = self.status(group_id, user_id).await?;
+        let (previous, freezes) = match streak.is_zero() {
+            true => (Duration::zero(), 0),
+            false => (card.updated_at - card.started_at, freezes),
+        };
+        self.update_updated_at_db(group_id, user_id, freezes)
+            .await?;
+        Ok(Punch {
+            streak,
+            previous,
+            freezes,
+        })
+    }
+    async fn status(&self, group_id: i64, user_id: i64) -> BotResult<Duration> {
+        let now = Local::now().naive_local();
+        let card = self.card(group_id, user_id).await?;
+        let score = self.score(&card, now);
+        if score.is_zero() {
+            // the streak ended with its last punch, unless it was already closed
+            if card.started_at < card.updated_at {
+                self.add_streak_db(
+                    group_id,
+                    user_id,
+                    card.started_at,
+                    card.updated_at,
+                    StreakEnd::Timeout,
+                )
+                .await?;
             }
+            self.update_started_at_db(group_id, user_id).await?;
         }
+        Ok(score)
     }
-    async fn derivative(&self, user_id: i64) {
-        self.status(user_id).await;
-        self.update_started_at_db(user_id).await.ok();
+    async fn derivative(&self, group_id: i64, user_id: i64) -> BotResult<()> {
+        let now = Local::now().naive_local();
+        let card = self.card(group_id, user_id).await?;
+        if self.score(&card, now).is_zero() {
+            return self.status(group_id, user_id).await.map(|_| ());
+        }
+        self.add_streak_db(group_id, user_id, card.started_at, now, StreakEnd::Relapse)
+            .await?;
+        self.update_started_at_db(group_id, user_id).await
     }
-    async fn ranking(
-        &self,
-        group_id: i64,
-        bot: &Bot,
-    ) -> Result<Vec<RankingListEntry>, Box<dyn Error + Send>> {
-        let member_list = bot
-            .api_request("get_group_member_list", json!({ "group_id": group_id }))
+    async fn card(&self, group_id: i64, user_id: i64) -> BotResult<TimeCard> {
+        self.get_card_db(group_id, user_id)
+            .await?
+            .ok_or(BotError::Database(sqlx::Error::RowNotFound))
+    }
+    async fn ranking(&self, group_id: i64) -> BotResult<Vec<RankingListEntry>> {
+        let now = Local::now().naive_local();
+        let mut ret: Vec<RankingListEntry> = self
+            .get_cards_db(group_id)
             .await?
-            .json::<MemberListExtractor>()
-            .await
-            .unwrap()
-            .data;
-        let mut ret: Vec<RankingListEntry> = Vec::new();
-        for entry in member_list {
-            ret.push(RankingListEntry {
-                user_id: entry.user_id,
-                score: self.status(entry.user_id).await,
-            });
-        }
-        ret.sort_by(|a, b| b.score.cmp(&a.score));
+            .iter()
+            .map(|card| RankingListEntry {
+                user_id: card.user_id,
+                score: self.score(card, now),
+            })
+            .collect();
+        ret.sort_by_key(|entry| std::cmp::Reverse(entry.score));
         Ok(ret)
     }
-    async fn get_started_at_db(
-        &self,
-        user_id: i64,
-    ) -> Result<NaiveDateTime, Box<dyn Error + Send>> {
-        sqlx::query!(
-            r"SELECT started_at FROM integral_time_card WHERE user_id=$1",
+    // the longest streak of every participant, finished or not
+    async fn best_ranking(&self, group_id: i64) -> BotResult<Vec<RankingListEntry>> {
+        let now = Local::now().naive_local();
+        let mut best: HashMap<i64, Duration> = self
+            .get_cards_db(group_id)
+            .await?
+            .iter()
+            .map(|card| (card.user_id, self.unrecorded(card, now)))
+            .collect();
+        for streak in self.get_group_streaks_db(group_id).await? {
+            let score = best.entry(streak.user_id).or_insert_with(Duration::zero);
+            *score = (*score).max(streak.ended_at - streak.started_at);
+        }
+        let mut ret: Vec<RankingListEntry> = best
+            .into_iter()
+            .map(|(user_id, score)| RankingListEntry { user_id, score })
+            .collect();
+        ret.sort_by_key(|entry| (std::cmp::Reverse(entry.score), entry.user_id));
+        Ok(ret)
+    }
+    async fn get_card_db(&self, group_id: i64, user_id: i64) -> BotResult<Option<TimeCard>> {
+        sqlx::query_as!(
+            TimeCard,
+            r"SELECT user_id, started_at, updated_at, freezes_used FROM integral_time_card
+            WHERE group_id = $1 AND user_id = $2",
+            group_id,
             user_id
         )
-        .fetch_one(&self.state.db)
+        .fetch_optional(&self.state.db)
         .await
-        .map(|row| row.started_at)
-        .map_err(|err| Box::new(err) as Box<dyn Error + Send>)
+        .map_err(BotError::from)
     }
-    async fn get_updated_at_db(
-        &self,
-        user_id: i64,
-    ) -> Result<NaiveDateTime, Box<dyn Error + Send>> {
-        sqlx::query!(
-            r"SELECT updated_at FROM integral_time_card WHERE user_id=$1",
-            user_id
+    async fn get_cards_db(&self, group_id: i64) -> BotResult<Vec<TimeCard>> {
+        sqlx::query_as!(
+            TimeCard,
+            r"SELECT user_id, started_at, updated_at, freezes_used FROM integral_time_card
+            WHERE group_id = $1",
+            group_id
         )
-        .fetch_one(&self.state.db)
+        .fetch_all(&self.state.db)
         .await
-        .map(|row| row.updated_at)
-        .map_err(|err| Box::new(err) as Box<dyn Error + Send>)
+        .map_err(BotError::from)
     }
-
-    async fn add_user_db(&self, user_id: i64) -> Result<(), Box<dyn Error + Send>> {
+    async fn add_user_db(&self, group_id: i64, user_id: i64) -> BotResult<()> {
         let now = Local::now().naive_local();
-        let long_time_ago = NaiveDateTime::from_timestamp(0, 0);
         sqlx::query!(
-            r"INSERT INTO integral_time_card VALUES ($1, $2, $3)",
+            r"INSERT INTO integral_time_card (group_id, user_id, started_at, updated_at)
+            VALUES ($1, $2, $3, $4)",
+            group_id,
             user_id,
             now,
-            long_time_ago
+            now
         )
         .execute(&self.state.db)
         .await
         .map(|_| ())
-        .map_err(|err| Box::new(err) as Box<dyn Error + Send>)
+        .map_err(BotError::from)
     }
-    async fn update_started_at_db(&self, user_id: i64) -> Result<(), Box<dyn Error + Send>> {
+    async fn update_started_at_db(&self, group_id: i64, user_id: i64) -> BotResult<()> {
         let now = Local::now().naive_local();
         sqlx::query!(
             r"UPDATE integral_time_card
-            SET started_at = $2
-            WHERE user_id = $1",
+            SET started_at = $3, freezes_used = 0
+            WHERE group_id = $1 AND user_id = $2",
+            group_id,
             user_id,
             now
         )
         .execute(&self.state.db)
         .await
         .map(|_| ())
-        .map_err(|err| Box::new(err) as Box<dyn Error + Send>)
+        .map_err(BotError::from)
     }
-    async fn update_updated_at_db(&self, user_id: i64) -> Result<(), Box<dyn Error + Send>> {
+    async fn update_updated_at_db(
+        &self,
+        group_id: i64,
+        user_id: i64,
+        freezes: i64,
+    ) -> BotResult<()> {
         let now = Local::now().naive_local();
         sqlx::query!(
             r"UPDATE integral_time_card
-            SET updated_at = $2
-            WHERE user_id = $1",
+            SET updated_at = $3, freezes_used = freezes_used + $4
+            WHERE group_id = $1 AND user_id = $2",
+            group_id,
             user_id,
-            now
+            now,
+            freezes
+        )
+        .execute(&self.state.db)
+        .await
+        .map(|_| ())
+        .map_err(BotError::from)
+    }
+    async fn get_streaks_db(&self, group_id: i64, user_id: i64) -> BotResult<Vec<Streak>> {
+        sqlx::query_as!(
+            Streak,
+            r"SELECT started_at, ended_at, reason FROM integral_streak
+            WHERE group_id = $1 AND user_id = $2
+            ORDER BY ended_at DESC",
+            group_id,
+            user_id
+        )
+        .fetch_all(&self.state.db)
+        .await
+        .map_err(BotError::from)
+    }
+    async fn get_group_streaks_db(&self, group_id: i64) -> BotResult<Vec<GroupStreak>> {
+        sqlx::query_as!(
+            GroupStreak,
+            r"SELECT user_id, started_at, ended_at FROM integral_streak
+            WHERE group_id = $1",
+            group_id
+        )
+        .fetch_all(&self.state.db)
+        .await
+        .map_err(BotError::from)
+    }
+    async fn add_streak_db(
+        &self,
+        group_id: i64,
+        user_id: i64,
+        started_at: NaiveDateTime,
+        ended_at: NaiveDateTime,
+        reason: StreakEnd,
+    ) -> BotResult<()> {
+        let reason = reason.as_str();
+        sqlx::query!(
+            r"INSERT INTO integral_streak (group_id, user_id, started_at, ended_at, reason)
+            VALUES ($1, $2, $3, $4, $5)",
+            group_id,
+            user_id,
+            started_at,
+            ended_at,
+            reason
         )
         .execute(&self.state.db)
         .await
         .map(|_| ())
-        .map_err(|err| Box::new(err) as Box<dyn Error + Send>)
+        .map_err(BotError::from)
+    }
+    async fn get_badges_db(&self, group_id: i64, user_id: i64) -> BotResult<Vec<String>> {
+        sqlx::query_scalar!(
+            r"SELECT badge FROM integral_badge
+            WHERE group_id = $1 AND user_id = $2
+            ORDER BY earned_at",
+            group_id,
+            user_id
+        )
+        .fetch_all(&self.state.db)
+        .await
+        .map_err(BotError::from)
+    }
+    async fn get_group_badges_db(&self, group_id: i64) -> BotResult<Vec<Badge>> {
+        sqlx::query_as!(
+            Badge,
+            r"SELECT user_id, badge FROM integral_badge
+            WHERE group_id = $1
+            ORDER BY earned_at",
+            group_id
+        )
+        .fetch_all(&self.state.db)
+        .await
+        .map_err(BotError::from)
+    }
+    // false when the user already had the badge
+    async fn add_badge_db(&self, group_id: i64, user_id: i64, badge: &str) -> BotResult<bool> {
+        let now = Local::now().naive_local();
+        sqlx::query!(
+            r"INSERT OR IGNORE INTO integral_badge VALUES ($1, $2, $3, $4)",
+            group_id,
+            user_id,
+            badge,
+            now
+        )
+        .execute(&self.state.db)
+        .await
+        .map(|result| result.rows_affected() == 1)
+        .map_err(BotError::from)
     }
 }
 
+const HISTORY_LISTED: usize = 10;
+
 enum Cmd {
     Punch,
     Status,
     Derivative,
-    Ranking,
+    Ranking(RankingQuery),
+    Best,
+    History,
+    Stats,
+}
+
+struct RankingQuery {
+    // rank the longest streaks instead of the current ones
+    best: bool,
+    view: RankingView,
+}
+
+enum RankingView {
+    // starting from 1
+    Page(usize),
+    AroundMe,
+}
+
+enum StreakEnd {
+    Relapse,
+    Timeout,
+}
+
+impl StreakEnd {
+    fn as_str(&self) -> &'static str {
+        match self {
+            StreakEnd::Relapse => "relapse",
+            StreakEnd::Timeout => "timeout",
+        }
+    }
+}
+
+struct TimeCard {
+    user_id: i64,
+    started_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    freezes_used: i64,
 }
 
-#[derive(Deserialize)]
-struct UserDataExtractor {
-    data: UserData,
+struct Streak {
+    started_at: NaiveDateTime,
+    ended_at: NaiveDateTime,
+    reason: String,
 }
 
-#[derive(Deserialize)]
-struct UserData {
-    nickname: String,
-    card: String,
+impl Streak {
+    fn duration(&self) -> Duration {
+        self.ended_at - self.started_at
+    }
+}
+
+struct GroupStreak {
     user_id: i64,
+    started_at: NaiveDateTime,
+    ended_at: NaiveDateTime,
 }
-#[derive(Deserialize)]
-struct MemberListExtractor {
-    data: Vec<UserData>,
+
+struct Badge {
+    user_id: i64,
+    badge: String,
+}
+
+struct Punch {
+    streak: Duration,
+    // the streak at the previous punch
+    previous: Duration,
+    // freeze tokens spent to keep the streak alive
+    freezes: i64,
 }
 
 struct RankingListEntry {
     user_id: i64,
     score: Duration,
 }
+
+inventory::submit! {
+    PluginFactory::new("integral", |config| {
+        Box::pin(async move {
+            Ok(vec![
+                Box::new(IntegralPlugin::new(plugin_config(config)?).await?) as BoxedPlugin
+            ])
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(window_hours: i64, deadline: Option<&str>, freeze_tokens: i64) -> IntegralPlugin {
+        IntegralPlugin {
+            state: IntegralPluginState {
+                db: SqlitePool::connect_lazy("sqlite::memory:").unwrap(),
+                deadline: deadline
+                    .map(|deadline| NaiveTime::parse_from_str(deadline, "%H:%M").unwrap()),
+                table_image: None,
+            },
+            config: IntegralPluginConfig {
+                window_hours,
+                deadline: deadline.map(str::to_string),
+                freeze_tokens,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn at(datetime: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn card(started_at: &str, updated_at: &str, freezes_used: i64) -> TimeCard {
+        TimeCard {
+            user_id: 2,
+            started_at: at(started_at),
+            updated_at: at(updated_at),
+            freezes_used,
+        }
+    }
+
+    #[tokio::test]
+    async fn window_crossing_midnight_expires_next_day() {
+        let plugin = plugin(6, None, 0);
+        assert_eq!(
+            plugin.expires_at(at("2022-10-01 22:00:00")),
+            at("2022-10-02 04:00:00")
+        );
+    }
+
+    #[tokio::test]
+    async fn deadline_after_midnight_keeps_late_punches_in_the_same_day() {
+        let plugin = plugin(24, Some("02:00"), 0);
+        let due = at("2022-10-03 02:00:00");
+        assert_eq!(plugin.expires_at(at("2022-10-01 23:30:00")), due);
+        assert_eq!(plugin.expires_at(at("2022-10-02 01:30:00")), due);
+        assert_eq!(
+            plugin.expires_at(at("2022-10-02 02:30:00")),
+            at("2022-10-04 02:00:00")
+        );
+    }
+
+    #[tokio::test]
+    async fn punch_exactly_at_deadline_counts_for_the_next_day() {
+        let plugin = plugin(24, Some("22:00"), 0);
+        assert_eq!(
+            plugin.expires_at(at("2022-10-01 21:59:59")),
+            at("2022-10-02 22:00:00")
+        );
+        assert_eq!(
+            plugin.expires_at(at("2022-10-01 22:00:00")),
+            at("2022-10-03 22:00:00")
+        );
+        let card = card("2022-09-30 20:00:00", "2022-10-01 21:00:00", 0);
+        assert_eq!(plugin.freezes_needed(&card, at("2022-10-02 21:59:59")), 0);
+        assert_eq!(plugin.freezes_needed(&card, at("2022-10-02 22:00:00")), 1);
+        assert!(plugin.score(&card, at("2022-10-02 22:00:00")).is_zero());
+    }
+
+    #[tokio::test]
+    async fn missed_periods_need_one_freeze_each() {
+        let plugin = plugin(24, None, 2);
+        let card = card("2022-10-01 08:00:00", "2022-10-02 08:00:00", 0);
+        assert_eq!(plugin.freezes_needed(&card, at("2022-10-03 07:59:59")), 0);
+        assert_eq!(plugin.freezes_needed(&card, at("2022-10-03 08:00:01")), 1);
+        assert_eq!(plugin.freezes_needed(&card, at("2022-10-04 08:00:01")), 2);
+        assert_eq!(plugin.freezes_needed(&card, at("2022-10-05 08:00:01")), 3);
+    }
+
+    #[tokio::test]
+    async fn score_survives_as_long_as_freezes_are_left() {
+        let plugin = plugin(24, None, 2);
+        let fresh = card("2022-10-01 08:00:00", "2022-10-02 08:00:00", 0);
+        let now = at("2022-10-04 08:00:01");
+        assert_eq!(plugin.score(&fresh, now), now - fresh.started_at);
+        assert!(plugin.score(&fresh, at("2022-10-05 08:00:01")).is_zero());
+        // a freeze already spent on this streak is gone
+        let frozen = card("2022-10-01 08:00:00", "2022-10-02 08:00:00", 1);
+        assert!(plugin.score(&frozen, now).is_zero());
+        assert_eq!(
+            plugin.score(&frozen, at("2022-10-03 08:00:01")),
+            at("2022-10-03 08:00:01") - frozen.started_at
+        );
+    }
+
+    #[tokio::test]
+    async fn deadline_freezes_count_whole_days() {
+        let plugin = plugin(24, Some("22:00"), 3);
+        let card = card("2022-10-01 20:00:00", "2022-10-01 21:00:00", 0);
+        assert_eq!(plugin.freezes_needed(&card, at("2022-10-03 21:00:00")), 1);
+        assert_eq!(plugin.freezes_needed(&card, at("2022-10-04 23:00:00")), 3);
+        assert!(!plugin.score(&card, at("2022-10-04 23:00:00")).is_zero());
+        assert!(plugin.score(&card, at("2022-10-05 22:00:00")).is_zero());
+    }
+}
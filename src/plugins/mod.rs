@@ -1,16 +1,13 @@
+mod registry;
+pub use registry::*;
 mod archive;
-pub use archive::*;
 mod echo;
-pub use echo::*;
+mod hokp;
+mod integral;
 mod question;
-pub use question::*;
-mod sauce;
-pub use sauce::*;
 mod randint;
-pub use randint::*;
-mod hokp;
-pub use hokp::*;
 mod repeat;
-pub use repeat::*;
-mod integral;
-pub use integral::*;
+mod sauce;
+mod script;
+#[cfg(feature = "wasm")]
+mod wasm;
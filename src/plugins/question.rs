@@ -1,69 +1,36 @@
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::error::Error;
 
 use regex::Regex;
-use tokio::sync::RwLock;
 
 use crate::bot::Bot;
-use crate::models::{CQEvent, Plugin, PluginSenario};
+use crate::error::BotResult;
+use crate::models::{CQEvent, Plugin, PluginFlow, PluginSenario};
+use crate::plugins::{plugin_config, BoxedPlugin, PluginFactory, PASSIVE_PRIORITY};
 
-#[derive(Clone)]
-struct QuestionPluginState {
-    // ignored_cnt: Arc<Mutex<usize>>,
-    last_question_timestamp: i64,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct QuestionPluginConfig {
-    // ignore_limit: Arc<Mutex<usize>>,
-    pub sleep_seconds: i64,
-}
+#[derive(Default, Serialize, Deserialize)]
+pub struct QuestionPluginConfig {}
 
 pub struct QuestionPlugin {
-    state: RwLock<QuestionPluginState>,
-    config: QuestionPluginConfig,
+    _config: QuestionPluginConfig,
 }
 
 impl QuestionPlugin {
     pub fn new(config: Option<QuestionPluginConfig>) -> Self {
         QuestionPlugin {
-            state: RwLock::new(QuestionPluginState {
-                // ignore_limit: Arc::new(Mutex::new(2)),
-                // ignored_cnt: Arc::new(Mutex::new(0)),
-                last_question_timestamp: 0,
-            }),
-            config: config.unwrap_or(QuestionPluginConfig { sleep_seconds: 0 }),
+            _config: config.unwrap_or_default(),
         }
     }
-    async fn question(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
-        let msg = event.raw_message.as_ref().unwrap();
-        let group_id = event.group_id.unwrap();
+    async fn question(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let msg = match event.raw_message.as_ref() {
+            Some(msg) => msg,
+            None => return Ok(PluginFlow::Pass),
+        };
         let re = Regex::new(r"^[\?？¿⁇❓❔]+$").unwrap();
         if !re.is_match(msg) {
-            return Ok(());
-        }
-        // let mut ignored_cnt = self.state.ignored_cnt.lock().await;
-        // *ignored_cnt += 1;
-        // if *ignored_cnt <= *self.state.ignore_limit.lock().await {
-        // return;
-        // }
-        // *ignored_cnt = 0;
-        let now_timestamp = chrono::Utc::now().timestamp();
-        let mut state = self.state.write().await;
-        if now_timestamp - state.last_question_timestamp < self.config.sleep_seconds {
-            return Ok(());
+            return Ok(PluginFlow::Pass);
         }
-        state.last_question_timestamp = now_timestamp;
-        bot.api_request(
-            "send_group_msg",
-            json!({
-                "group_id": group_id,
-                "message": msg,
-            }),
-        )
-        .await?;
-        Ok(())
+        bot.reply(&event, msg.as_str()).await?;
+        Ok(PluginFlow::Consumed)
     }
 }
 
@@ -76,18 +43,30 @@ impl Plugin for QuestionPlugin {
         "自动复读问号"
     }
     fn help(&self) -> &'static str {
-        ""
+        "有人只发问号时跟着发问号"
     }
     fn senario(&self) -> PluginSenario {
-        PluginSenario::Group
+        PluginSenario::Both
     }
-    async fn handle(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
+    fn category(&self) -> &'static str {
+        "fun"
+    }
+    fn is_passive(&self) -> bool {
+        true
+    }
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
         match event.post_type.as_str() {
-            "message" => match event.message_type.as_ref().unwrap().as_str() {
-                "group" => self.question(event, bot).await,
-                _ => Ok(()),
-            },
-            _ => Ok(()),
+            "message" => self.question(event, bot).await,
+            _ => Ok(PluginFlow::Pass),
         }
     }
 }
+
+inventory::submit! {
+    PluginFactory::new("question", |config| {
+        Box::pin(async move {
+            Ok(vec![Box::new(QuestionPlugin::new(plugin_config(config)?)) as BoxedPlugin])
+        })
+    })
+    .with_priority(PASSIVE_PRIORITY)
+}
@@ -1,11 +1,11 @@
 use rand::Rng;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::error::Error;
 
 use crate::bot::Bot;
-use crate::models::{CQEvent, Plugin, PluginSenario};
+use crate::error::{BotError, BotResult};
+use crate::models::{CQEvent, Plugin, PluginFlow, PluginSenario};
+use crate::plugins::{plugin_config, BoxedPlugin, PluginFactory};
 #[derive(Deserialize, Serialize, Default)]
 pub struct RandintPluginConfig;
 #[allow(dead_code)]
@@ -18,43 +18,31 @@ impl RandintPlugin {
             config: config.unwrap_or_default(),
         }
     }
-    async fn randint(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
-        let msg = event.raw_message.as_ref().unwrap();
+    async fn randint(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let msg = match event.raw_message.as_ref() {
+            Some(msg) => msg,
+            None => return Ok(PluginFlow::Pass),
+        };
         let re = Regex::new(r"^>randint\s+(?P<min>\d+)\s+(?P<max>\d+)\s*$").unwrap();
         if !re.is_match(msg) {
-            return Ok(());
+            return Ok(PluginFlow::Pass);
         }
-        let min = re.replace_all(&msg, "$min").parse::<u128>();
-        let max = re.replace_all(&msg, "$max").parse::<u128>();
-        if let Err(_) = min {
-            return Ok(());
-        }
-        if let Err(_) = max {
-            return Ok(());
-        }
-        let min = min.unwrap();
-        let max = max.unwrap();
+        let min = re.replace_all(msg, "$min").parse::<u128>();
+        let max = re.replace_all(msg, "$max").parse::<u128>();
+        let (min, max) = match (min, max) {
+            (Ok(min), Ok(max)) => (min, max),
+            _ => {
+                return Err(BotError::UserInput(
+                    bot.tr(&event).t("randint.out_of_range"),
+                ))
+            }
+        };
         if min > max {
-            bot.api_request(
-                "send_group_msg",
-                json!({
-                    "group_id": event.group_id.as_ref().unwrap(),
-                    "message": "homo特有的10比9大",
-                }),
-            )
-            .await?;
-            return Ok(());
+            return Err(BotError::UserInput(bot.tr(&event).t("randint.min_gt_max")));
         }
         let rand = rand::thread_rng().gen_range(min..=max);
-        bot.api_request(
-            "send_group_msg",
-            json!({
-                "group_id": event.group_id.as_ref().unwrap(),
-                "message": format!("{}", rand),
-            }),
-        )
-        .await?;
-        Ok(())
+        bot.reply(&event, rand.to_string()).await?;
+        Ok(PluginFlow::Consumed)
     }
 }
 
@@ -70,15 +58,26 @@ impl Plugin for RandintPlugin {
         "用法:\r\n>randint <min> <max>\r\n\tmin: 最小值\r\n\tmax: 最大值\r\n\r\n返回一个[min, max]之间的随机非负整数\r\n注意: min, max在u128范围内"
     }
     fn senario(&self) -> PluginSenario {
-        PluginSenario::Group
+        PluginSenario::Both
+    }
+    fn category(&self) -> &'static str {
+        "tools"
     }
-    async fn handle(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
+    fn examples(&self) -> &'static [&'static str] {
+        &[">randint 1 100"]
+    }
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
         match event.post_type.as_str() {
-            "message" => match event.message_type.as_ref().unwrap().as_str() {
-                "group" => self.randint(event, bot).await,
-                _ => Ok(()),
-            },
-            _ => Ok(()),
+            "message" => self.randint(event, bot).await,
+            _ => Ok(PluginFlow::Pass),
         }
     }
 }
+
+inventory::submit! {
+    PluginFactory::new("randint", |config| {
+        Box::pin(async move {
+            Ok(vec![Box::new(RandintPlugin::new(plugin_config(config)?)) as BoxedPlugin])
+        })
+    })
+}
@@ -0,0 +1,121 @@
+use std::cmp::Reverse;
+
+use futures::future::BoxFuture;
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+
+use crate::error::{BotError, BotResult};
+use crate::models::{Plugin, PluginsConfig};
+
+pub type BoxedPlugin = Box<dyn Plugin + Send + Sync>;
+
+type BuildFn = fn(Option<toml::Value>) -> BoxFuture<'static, BotResult<Vec<BoxedPlugin>>>;
+
+// Every plugin module submits one factory, which gets the `[plugins.<name>]`
+// table without the `enabled` and `priority` keys, or None when the table is
+// absent or empty. Hosts like `script` may build several plugins at once.
+pub struct PluginFactory {
+    pub name: &'static str,
+    pub priority: i64,
+    pub build: BuildFn,
+}
+
+impl PluginFactory {
+    pub const fn new(name: &'static str, build: BuildFn) -> Self {
+        PluginFactory {
+            name,
+            priority: 0,
+            build,
+        }
+    }
+    pub const fn with_priority(mut self, priority: i64) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+// passive plugins go after commands so they never see a consumed command
+pub const PASSIVE_PRIORITY: i64 = -100;
+
+inventory::collect!(PluginFactory);
+
+pub fn plugin_config<T: DeserializeOwned>(config: Option<toml::Value>) -> BotResult<Option<T>> {
+    config
+        .map(|config| config.try_into())
+        .transpose()
+        .map_err(|err: toml::de::Error| BotError::Config(err.to_string()))
+}
+
+/// Builds the plugins of every registered factory that isn't disabled in
+/// config, higher priority first.
+pub async fn build_plugins(config: &PluginsConfig) -> BotResult<Vec<BoxedPlugin>> {
+    for name in config.keys() {
+        if !inventory::iter::<PluginFactory>().any(|factory| factory.name == name) {
+            warn!("config for unknown plugin {name} is ignored");
+        }
+    }
+    let mut factories = Vec::new();
+    for factory in inventory::iter::<PluginFactory>() {
+        let mut table = match config.get(factory.name) {
+            Some(toml::Value::Table(table)) => table.clone(),
+            Some(_) => {
+                return Err(BotError::Config(format!(
+                    "plugins.{} must be a table",
+                    factory.name
+                )))
+            }
+            None => toml::value::Table::new(),
+        };
+        let enabled = match table.remove("enabled") {
+            Some(toml::Value::Boolean(enabled)) => enabled,
+            Some(_) => {
+                return Err(BotError::Config(format!(
+                    "plugins.{}.enabled must be a boolean",
+                    factory.name
+                )))
+            }
+            None => true,
+        };
+        let priority = match table.remove("priority") {
+            Some(toml::Value::Integer(priority)) => priority,
+            Some(_) => {
+                return Err(BotError::Config(format!(
+                    "plugins.{}.priority must be an integer",
+                    factory.name
+                )))
+            }
+            None => factory.priority,
+        };
+        if !enabled {
+            info!("plugin {} is disabled", factory.name);
+            continue;
+        }
+        let table = match table.is_empty() {
+            true => None,
+            false => Some(toml::Value::Table(table)),
+        };
+        factories.push((priority, factory, table));
+    }
+    factories.sort_by_key(|(priority, factory, _)| (Reverse(*priority), factory.name));
+    let mut plugins = Vec::new();
+    for (_, factory, table) in factories {
+        let built = (factory.build)(table)
+            .await
+            .map_err(|err| BotError::Config(format!("plugin {}: {}", factory.name, err)))?;
+        // disabled plugins, access rules, cooldowns and help go by name
+        for plugin in built {
+            if plugins
+                .iter()
+                .any(|other: &BoxedPlugin| other.name() == plugin.name())
+            {
+                return Err(BotError::Config(format!(
+                    "plugin {}: another plugin is already named {}",
+                    factory.name,
+                    plugin.name()
+                )));
+            }
+            plugins.push(plugin);
+        }
+    }
+    Ok(plugins)
+}
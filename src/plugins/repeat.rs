@@ -1,25 +1,22 @@
-use std::error::Error;
-
 use log::debug;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use tokio::sync::RwLock;
 
 use crate::{
     bot::Bot,
-    models::{CQEvent, Plugin, PluginSenario},
+    error::BotResult,
+    models::{CQEvent, Plugin, PluginFlow, PluginSenario},
+    plugins::{plugin_config, BoxedPlugin, PluginFactory, PASSIVE_PRIORITY},
 };
 
 #[derive(Default, Debug)]
 struct RepeatPluginState {
     target_msg: Option<String>,
     target_cnt: i64,
-    last_msg_timestamp: i64,
 }
 #[derive(Default, Deserialize, Serialize)]
 pub struct RepeatPluginConfig {
     threshold: i64,
-    pub sleep_seconds: i64,
 }
 pub struct RepeatPlugin {
     state: RwLock<RepeatPluginState>,
@@ -37,35 +34,29 @@ impl Plugin for RepeatPlugin {
     }
 
     fn help(&self) -> &'static str {
-        ""
+        "同一条消息被连续发送多次后跟着复读"
     }
 
     fn senario(&self) -> PluginSenario {
         PluginSenario::Group
     }
 
-    async fn handle(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
+    fn category(&self) -> &'static str {
+        "fun"
+    }
+
+    fn is_passive(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
         match event.post_type.as_str() {
-            "message" => match event.message_type.as_ref().unwrap().as_str() {
-                "group" => {
-                    self.set_state(event.clone()).await?;
-                    {
-                        let now_timestamp = chrono::Utc::now().timestamp();
-                        let state = self.state.read().await;
-                        if now_timestamp - state.last_msg_timestamp < self.config.sleep_seconds {
-                            debug!(
-                                "plugin sleeping. {} seconds remaining. returning...",
-                                self.config.sleep_seconds + state.last_msg_timestamp
-                                    - now_timestamp
-                            );
-                            return Ok(());
-                        }
-                    }
-                    self.do_repeat(event, bot).await
-                }
-                _ => Ok(()),
-            },
-            _ => Ok(()),
+            "message" => {
+                self.set_state(event.clone()).await?;
+                self.do_repeat(event, bot).await?;
+                Ok(PluginFlow::Pass)
+            }
+            _ => Ok(PluginFlow::Pass),
         }
     }
 }
@@ -77,7 +68,7 @@ impl RepeatPlugin {
             config: config.unwrap_or_default(),
         }
     }
-    async fn set_state(&self, event: CQEvent) -> Result<(), Box<dyn Error + Send>> {
+    async fn set_state(&self, event: CQEvent) -> BotResult<()> {
         let mut state = self.state.write().await;
         match state.target_msg {
             None => {
@@ -86,8 +77,7 @@ impl RepeatPlugin {
                 debug!("state: {:?}", state);
             }
             Some(ref msg) => {
-                let new_msg = event.raw_message.as_ref().unwrap();
-                if new_msg == msg {
+                if event.raw_message.as_ref() == Some(msg) {
                     state.target_cnt += 1;
                 } else {
                     state.target_msg = event.raw_message;
@@ -98,25 +88,24 @@ impl RepeatPlugin {
         }
         Ok(())
     }
-    async fn do_repeat(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
+    async fn do_repeat(&self, event: CQEvent, bot: &Bot) -> BotResult<()> {
         let mut state = self.state.write().await;
-        if let Some(ref msg) = state.target_msg {
-            let new_msg = event.raw_message.as_ref().unwrap();
+        if let (Some(msg), Some(new_msg)) = (&state.target_msg, &event.raw_message) {
             if new_msg == msg && state.target_cnt >= self.config.threshold {
-                bot.api_request(
-                    "send_group_msg",
-                    json!({
-                        "group_id": event.group_id.unwrap(),
-                        "message": new_msg,
-                    }),
-                )
-                .await?;
+                bot.reply(&event, new_msg.as_str()).await?;
                 state.target_msg = None;
                 state.target_cnt = 0;
-                let now_timestamp = chrono::Utc::now().timestamp();
-                state.last_msg_timestamp = now_timestamp;
             }
         }
         Ok(())
     }
 }
+
+inventory::submit! {
+    PluginFactory::new("repeat", |config| {
+        Box::pin(async move {
+            Ok(vec![Box::new(RepeatPlugin::new(plugin_config(config)?)) as BoxedPlugin])
+        })
+    })
+    .with_priority(PASSIVE_PRIORITY)
+}
@@ -1,11 +1,11 @@
 use regex::Regex;
-use reqwest;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::error::Error;
 
-use crate::bot::Bot;
-use crate::models::{CQEvent, Plugin, PluginSenario};
+use crate::bot::{Bot, ReplyOptions};
+use crate::error::{BotError, BotResult};
+use crate::models::{CQEvent, Plugin, PluginFlow, PluginSenario};
+use crate::plugins::{plugin_config, BoxedPlugin, PluginFactory};
+use crate::session::{SessionOptions, SessionReply};
 
 #[derive(Deserialize, Serialize, Debug, Default)]
 pub struct SaucePluginConfig {
@@ -20,61 +20,92 @@ impl SaucePlugin {
             config: config.unwrap_or_default(),
         }
     }
-    async fn sauce(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
-        let msg = event.raw_message.as_ref().unwrap();
+    async fn sauce(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let msg = match event.raw_message.as_ref() {
+            Some(msg) => msg,
+            None => return Ok(PluginFlow::Pass),
+        };
         let re =
             Regex::new(r"^>sauce\s*\[CQ:image,[^\]]*url=(?P<img_url>[^,\]]+)[^\]]*\]\s*$").unwrap();
-        if !re.is_match(msg) {
-            return Ok(());
-        }
-        let img_url = re.replace_all(&msg, "$img_url").to_string();
+        let (event, img_url) = if re.is_match(msg) {
+            let img_url = re.replace_all(msg, "$img_url").to_string();
+            (event, img_url)
+        } else if Regex::new(r"^>sauce\s*$").unwrap().is_match(msg) {
+            match self.wait_image(event, bot).await? {
+                Some(found) => found,
+                None => return Ok(PluginFlow::Consumed),
+            }
+        } else {
+            return Ok(PluginFlow::Pass);
+        };
+        let api_key = self
+            .config
+            .api_key
+            .as_ref()
+            .ok_or_else(|| BotError::Config("sauce.api_key is not set".to_string()))?;
         let resp = reqwest::Client::new()
             .get("https://saucenao.com/search.php")
             .query(&[
                 ("db", "999"),
                 ("output_type", "2"),
                 ("numres", "1"),
-                ("api_key", self.config.api_key.as_ref().unwrap()),
+                ("api_key", api_key),
                 ("url", &img_url),
             ])
             .send()
-            .await
-            .map_err(|err| Box::new(err) as Box<dyn Error + Send>)?
+            .await?
             .json::<SauceResponse>()
-            .await
-            .unwrap();
-        if resp.results.len() == 0 {
-            bot.api_request(
-                "send_group_msg",
-                json!({
-                    "group_id": event.group_id.as_ref().unwrap(),
-                    "message": "没有找到结果",
-                }),
-            )
             .await?;
-            return Ok(());
+        if resp.results.is_empty() {
+            bot.reply_with(&event, bot.tr(&event).t("sauce.no_result"), QUOTE)
+                .await?;
+            return Ok(PluginFlow::Consumed);
         }
         for result in resp.results {
-            let msg = format!(
-                "相似度 {similarity}\r\n[CQ:image,file={img_url}]\r\n{result_url}",
-                similarity = result.header.similarity,
-                img_url = result.header.thumbnail,
-                result_url = match result.data.ext_urls {
-                    Some(urls) => urls.join("\r\n"),
-                    None => String::new(),
-                }
+            let result_url = match result.data.ext_urls {
+                Some(urls) => urls.join("\r\n"),
+                None => String::new(),
+            };
+            let msg = bot.tr(&event).t_args(
+                "sauce.result",
+                &[
+                    ("similarity", &result.header.similarity),
+                    ("img_url", &result.header.thumbnail),
+                    ("result_url", &result_url),
+                ],
             );
-
-            bot.api_request(
-                "send_group_msg",
-                json!({
-                    "group_id": event.group_id.unwrap(),
-                    "message": msg,
-                }),
-            )
-            .await?;
+            bot.reply_with(&event, msg, QUOTE).await?;
         }
-        Ok(())
+        Ok(PluginFlow::Consumed)
+    }
+    async fn wait_image(&self, event: CQEvent, bot: &Bot) -> BotResult<Option<(CQEvent, String)>> {
+        let tr = bot.tr(&event);
+        let options = SessionOptions {
+            cancel_word: tr.t("session.cancel_word"),
+            ..Default::default()
+        };
+        let prompt = tr.t_args("sauce.send_image", &[("cancel_word", &options.cancel_word)]);
+        bot.reply_with(&event, prompt, QUOTE).await?;
+        let next = match bot.wait_next_message(&event, options).await {
+            SessionReply::Message(next) => *next,
+            SessionReply::Cancelled => {
+                bot.reply(&event, tr.t("sauce.cancelled")).await?;
+                return Ok(None);
+            }
+            SessionReply::Timeout => {
+                bot.reply_with(&event, tr.t("sauce.timeout"), QUOTE).await?;
+                return Ok(None);
+            }
+        };
+        let re = Regex::new(r"\[CQ:image,[^\]]*url=(?P<img_url>[^,\]]+)[^\]]*\]").unwrap();
+        let img_url = match re.captures(next.raw_message.as_deref().unwrap_or_default()) {
+            Some(caps) => caps["img_url"].to_string(),
+            None => {
+                bot.reply_with(&next, tr.t("sauce.no_image"), QUOTE).await?;
+                return Ok(None);
+            }
+        };
+        Ok(Some((next, img_url)))
     }
 }
 
@@ -87,19 +118,30 @@ impl Plugin for SaucePlugin {
         "SauceNAO以图搜图"
     }
     fn help(&self) -> &'static str {
-        "用法:\r\n>sauce <图片>"
+        "用法:\r\n>sauce <图片>\r\n>sauce 之后再发送图片"
     }
     fn senario(&self) -> PluginSenario {
-        PluginSenario::Group
+        PluginSenario::Both
+    }
+    fn category(&self) -> &'static str {
+        "tools"
     }
-    async fn handle(&self, event: CQEvent, bot: &Bot) -> Result<(), Box<dyn Error + Send>> {
+    fn examples(&self) -> &'static [&'static str] {
+        &[">sauce [图片]"]
+    }
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
         match event.post_type.as_str() {
             "message" => self.sauce(event, bot).await,
-            _ => Ok(()),
+            _ => Ok(PluginFlow::Pass),
         }
     }
 }
 
+const QUOTE: ReplyOptions = ReplyOptions {
+    quote: true,
+    at_sender: false,
+};
+
 #[derive(Deserialize)]
 struct SauceResponse {
     // header: Header,
@@ -122,3 +164,11 @@ struct SauceResultHeader {
 struct SauceResultData {
     ext_urls: Option<Vec<String>>,
 }
+
+inventory::submit! {
+    PluginFactory::new("sauce", |config| {
+        Box::pin(async move {
+            Ok(vec![Box::new(SaucePlugin::new(plugin_config(config)?)) as BoxedPlugin])
+        })
+    })
+}
@@ -0,0 +1,342 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::bot::Bot;
+use crate::error::{BotError, BotResult};
+use crate::models::{CQEvent, Plugin, PluginFlow, PluginSenario};
+use crate::plugins::{plugin_config, BoxedPlugin, PluginFactory};
+use crate::storage::PluginStorage;
+
+#[derive(Deserialize, Serialize)]
+pub struct ScriptPluginConfig {
+    pub dir: String,
+    // where each script keeps its storage, one json file per script
+    pub storage_dir: String,
+    pub max_operations: u64,
+    pub timeout_ms: u64,
+    // hosts scripts may reach with `http_get`
+    #[serde(default)]
+    pub http_hosts: Vec<String>,
+    // chats scripts may post into with `send_group` and `send_private`
+    // besides the one the event came from
+    #[serde(default)]
+    pub send_groups: Vec<i64>,
+    #[serde(default)]
+    pub send_users: Vec<i64>,
+}
+
+impl Default for ScriptPluginConfig {
+    fn default() -> Self {
+        ScriptPluginConfig {
+            dir: "scripts".to_string(),
+            storage_dir: "scripts/data".to_string(),
+            max_operations: 100_000,
+            timeout_ms: 2000,
+            http_hosts: Vec::new(),
+            send_groups: Vec::new(),
+            send_users: Vec::new(),
+        }
+    }
+}
+
+enum Action {
+    Reply(String),
+    SendGroup(i64, String),
+    SendPrivate(i64, String),
+}
+
+struct ScriptMeta {
+    name: &'static str,
+    description: &'static str,
+    help: &'static str,
+    category: &'static str,
+    examples: &'static [&'static str],
+    senario: PluginSenario,
+}
+
+pub struct ScriptPlugin {
+    meta: ScriptMeta,
+    ast: Arc<AST>,
+    config: Arc<ScriptPluginConfig>,
+    storage: Arc<PluginStorage>,
+    client: reqwest::Client,
+}
+
+fn leak(s: String) -> &'static str {
+    // scripts are loaded once at startup and live as long as the bot
+    Box::leak(s.into_boxed_str())
+}
+
+impl ScriptPlugin {
+    pub fn load_dir(config: Option<ScriptPluginConfig>) -> Vec<ScriptPlugin> {
+        let config = Arc::new(config.unwrap_or_default());
+        let entries = match fs::read_dir(&config.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "rhai"))
+            .collect();
+        paths.sort();
+        let mut ret: Vec<ScriptPlugin> = Vec::new();
+        for path in paths {
+            match Self::load(&path, config.clone()) {
+                Ok(plugin) if ret.iter().any(|other| other.meta.name == plugin.meta.name) => {
+                    warn!(
+                        "script {:?} is ignored, another script is named {}",
+                        path, plugin.meta.name
+                    );
+                }
+                Ok(plugin) => {
+                    info!("loaded script plugin {} from {:?}", plugin.meta.name, path);
+                    ret.push(plugin);
+                }
+                Err(err) => warn!("failed to load script {:?}: {}", path, err),
+            }
+        }
+        ret
+    }
+    fn load(path: &Path, config: Arc<ScriptPluginConfig>) -> BotResult<Self> {
+        let engine = Self::sandboxed_engine(&config);
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|err| BotError::Script(err.to_string()))?;
+        let meta: Map = engine
+            .call_fn(&mut Scope::new(), &ast, "meta", ())
+            .map_err(|err| BotError::Script(err.to_string()))?;
+        let text = |key: &str| -> String {
+            meta.get(key)
+                .and_then(|value| value.clone().into_string().ok())
+                .unwrap_or_default()
+        };
+        let name = text("name");
+        // the name doubles as the file name of the storage
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_name {
+            return Err(BotError::Script(format!(
+                "meta() must return a name made of letters, digits, _ and -, got {name:?}"
+            )));
+        }
+        let senario = match text("senario").as_str() {
+            "private" => PluginSenario::Private,
+            "group" => PluginSenario::Group,
+            _ => PluginSenario::Both,
+        };
+        let examples: Vec<&'static str> = meta
+            .get("examples")
+            .and_then(|value| value.clone().try_cast::<Array>())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|example| example.into_string().ok())
+            .map(leak)
+            .collect();
+        let category = match text("category") {
+            category if category.is_empty() => "other".to_string(),
+            category => category,
+        };
+        let storage = Arc::new(PluginStorage::open(&config.storage_dir, &name));
+        Ok(ScriptPlugin {
+            meta: ScriptMeta {
+                name: leak(name),
+                description: leak(text("description")),
+                help: leak(text("help")),
+                category: leak(category),
+                examples: Box::leak(examples.into_boxed_slice()),
+                senario,
+            },
+            ast: Arc::new(ast),
+            config,
+            storage,
+            client: reqwest::Client::new(),
+        })
+    }
+    fn sandboxed_engine(config: &ScriptPluginConfig) -> Engine {
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+        engine.set_max_call_levels(32);
+        engine.set_max_expr_depths(64, 32);
+        engine.set_max_string_size(64 * 1024);
+        engine.set_max_array_size(10_000);
+        engine.set_max_map_size(10_000);
+        engine.disable_symbol("eval");
+        engine
+    }
+    // runs `handle(event)` of the script on a blocking thread and returns the
+    // actions it asked for, the event is consumed when `handle` returns true
+    async fn run(&self, event: &CQEvent) -> BotResult<(PluginFlow, Vec<Action>)> {
+        let (event_group, event_user) = (event.group_id, event.user_id);
+        let event =
+            rhai::serde::to_dynamic(event).map_err(|err| BotError::Script(err.to_string()))?;
+        let ast = self.ast.clone();
+        let config = self.config.clone();
+        let storage = self.storage.clone();
+        let client = self.client.clone();
+        let runtime = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || {
+            let actions = Arc::new(Mutex::new(Vec::new()));
+            let mut engine = Self::sandboxed_engine(&config);
+            let deadline = Instant::now() + Duration::from_millis(config.timeout_ms);
+            engine.on_progress(move |_| match Instant::now() > deadline {
+                true => Some("timeout".into()),
+                false => None,
+            });
+            let acts = actions.clone();
+            engine.register_fn("reply", move |message: &str| {
+                acts.lock()
+                    .unwrap()
+                    .push(Action::Reply(message.to_string()));
+            });
+            let acts = actions.clone();
+            let send_groups = config.send_groups.clone();
+            engine.register_fn(
+                "send_group",
+                move |group_id: i64, message: &str| -> Result<(), Box<EvalAltResult>> {
+                    if event_group != Some(group_id) && !send_groups.contains(&group_id) {
+                        return Err(format!("sending to group {group_id} is not allowed").into());
+                    }
+                    acts.lock()
+                        .unwrap()
+                        .push(Action::SendGroup(group_id, message.to_string()));
+                    Ok(())
+                },
+            );
+            let acts = actions.clone();
+            let send_users = config.send_users.clone();
+            engine.register_fn(
+                "send_private",
+                move |user_id: i64, message: &str| -> Result<(), Box<EvalAltResult>> {
+                    if event_user != Some(user_id) && !send_users.contains(&user_id) {
+                        return Err(format!("sending to user {user_id} is not allowed").into());
+                    }
+                    acts.lock()
+                        .unwrap()
+                        .push(Action::SendPrivate(user_id, message.to_string()));
+                    Ok(())
+                },
+            );
+            let store = storage.clone();
+            engine.register_fn("storage_get", move |key: &str| -> Dynamic {
+                store
+                    .get(key)
+                    .and_then(|value| rhai::serde::to_dynamic(value).ok())
+                    .unwrap_or(Dynamic::UNIT)
+            });
+            let store = storage.clone();
+            engine.register_fn(
+                "storage_set",
+                move |key: &str, value: Dynamic| -> Result<(), Box<EvalAltResult>> {
+                    let value: serde_json::Value = rhai::serde::from_dynamic(&value)?;
+                    store.set(key, value);
+                    Ok(())
+                },
+            );
+            let http_hosts = config.http_hosts.clone();
+            engine.register_fn(
+                "http_get",
+                move |url: &str| -> Result<String, Box<EvalAltResult>> {
+                    let parsed = reqwest::Url::parse(url).map_err(|err| err.to_string())?;
+                    let allowed = parsed
+                        .host_str()
+                        .is_some_and(|host| http_hosts.iter().any(|allowed| allowed == host));
+                    if !allowed {
+                        return Err(format!("host of {url} is not allowed").into());
+                    }
+                    runtime
+                        .block_on(async {
+                            client
+                                .get(parsed)
+                                .timeout(Duration::from_secs(5))
+                                .send()
+                                .await?
+                                .text()
+                                .await
+                        })
+                        .map_err(|err| err.to_string().into())
+                },
+            );
+            let consumed: Dynamic = engine
+                .call_fn(&mut Scope::new(), &ast, "handle", (event,))
+                .map_err(|err| BotError::Script(err.to_string()))?;
+            storage.flush()?;
+            let flow = match consumed.as_bool() {
+                Ok(true) => PluginFlow::Consumed,
+                _ => PluginFlow::Pass,
+            };
+            let actions = std::mem::take(&mut *actions.lock().unwrap());
+            Ok((flow, actions))
+        })
+        .await
+        .map_err(|err| BotError::Script(err.to_string()))?
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for ScriptPlugin {
+    fn name(&self) -> &'static str {
+        self.meta.name
+    }
+    fn description(&self) -> &'static str {
+        self.meta.description
+    }
+    fn help(&self) -> &'static str {
+        self.meta.help
+    }
+    fn senario(&self) -> PluginSenario {
+        self.meta.senario
+    }
+    fn category(&self) -> &'static str {
+        self.meta.category
+    }
+    fn examples(&self) -> &'static [&'static str] {
+        self.meta.examples
+    }
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let (flow, actions) = self.run(&event).await?;
+        for action in actions {
+            match action {
+                Action::Reply(message) => {
+                    bot.reply(&event, message).await?;
+                }
+                Action::SendGroup(group_id, message) => {
+                    bot.api_request::<serde_json::Value>(
+                        "send_group_msg",
+                        json!({ "group_id": group_id, "message": message }),
+                    )
+                    .await?;
+                }
+                Action::SendPrivate(user_id, message) => {
+                    bot.api_request::<serde_json::Value>(
+                        "send_private_msg",
+                        json!({ "user_id": user_id, "message": message }),
+                    )
+                    .await?;
+                }
+            }
+        }
+        Ok(flow)
+    }
+}
+
+inventory::submit! {
+    PluginFactory::new("script", |config| {
+        Box::pin(async move {
+            Ok(ScriptPlugin::load_dir(plugin_config(config)?)
+                .into_iter()
+                .map(|plugin| Box::new(plugin) as BoxedPlugin)
+                .collect())
+        })
+    })
+}
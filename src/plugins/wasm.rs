@@ -0,0 +1,411 @@
+// A module exports `memory`, `alloc(len) -> ptr`, `name`, `description`,
+// `help` and optionally `category` (each `() -> i64`, a packed string),
+// `senario() -> i32` (0 private, 1 group, 2 both) and
+// `handle(ptr, len) -> i32` which gets the event as json and returns 0 to
+// pass it on, 1 to consume it and a negative code on failure. The host imports live in the `intrude` namespace:
+//   send(ptr, len)                       reply to the current chat
+//   call(ptr, len) -> i32                `{"action", "params"}`, 0 if queued,
+//                                        -1 on bad json, -2 if not permitted
+//   storage_get(ptr, len) -> i64         packed json value or -1
+//   storage_set(kptr, klen, vptr, vlen)  -> 0, or -1 on bad json
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasmtime::{Caller, Engine, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::bot::Bot;
+use crate::error::{BotError, BotResult};
+use crate::models::{CQEvent, Plugin, PluginFlow, PluginSenario};
+use crate::plugins::{plugin_config, BoxedPlugin, PluginFactory};
+use crate::storage::PluginStorage;
+
+#[derive(Deserialize, Serialize)]
+pub struct WasmPluginConfig {
+    pub dir: String,
+    pub storage_dir: String,
+    // fuel given to each call into a module, roughly one unit per instruction
+    pub fuel: u64,
+    // bytes of linear memory an instance may grow to
+    #[serde(default = "default_max_memory")]
+    pub max_memory: usize,
+    // OneBot actions each plugin may call through `call`, by the file stem of
+    // its module; replying to the current chat through `send` is always allowed
+    #[serde(default)]
+    pub permissions: HashMap<String, Vec<String>>,
+}
+
+fn default_max_memory() -> usize {
+    16 * 1024 * 1024
+}
+
+impl Default for WasmPluginConfig {
+    fn default() -> Self {
+        WasmPluginConfig {
+            dir: "wasm".to_string(),
+            storage_dir: "wasm/data".to_string(),
+            fuel: 10_000_000,
+            max_memory: default_max_memory(),
+            permissions: HashMap::new(),
+        }
+    }
+}
+
+enum Action {
+    Reply(String),
+    Call(String, Value),
+}
+
+#[derive(Deserialize)]
+struct CallReq {
+    action: String,
+    #[serde(default)]
+    params: Value,
+}
+
+struct HostState {
+    actions: Vec<Action>,
+    allowed_actions: Vec<String>,
+    storage: Arc<PluginStorage>,
+    limits: StoreLimits,
+}
+
+pub struct WasmPlugin {
+    name: &'static str,
+    description: &'static str,
+    help: &'static str,
+    category: &'static str,
+    senario: PluginSenario,
+    engine: Engine,
+    module: Module,
+    linker: Arc<Linker<HostState>>,
+    fuel: u64,
+    max_memory: usize,
+    allowed_actions: Vec<String>,
+    storage: Arc<PluginStorage>,
+}
+
+fn leak(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+// strings cross the boundary as a pointer into the module's memory and a
+// length, packed into one i64 as `ptr << 32 | len`
+fn unpack(packed: i64) -> (usize, usize) {
+    ((packed >> 32) as u32 as usize, packed as u32 as usize)
+}
+
+fn read_string(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32) -> wasmtime::Result<String> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| wasmtime::Error::msg("module does not export memory"))?;
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    // the length comes from the guest, check it before allocating
+    if ptr
+        .checked_add(len)
+        .is_none_or(|end| end > memory.data_size(&caller))
+    {
+        return Err(wasmtime::Error::msg("string out of bounds"));
+    }
+    let mut buf = vec![0; len];
+    memory.read(&caller, ptr, &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+fn write_string(caller: &mut Caller<'_, HostState>, content: &str) -> wasmtime::Result<i64> {
+    let alloc = caller
+        .get_export("alloc")
+        .and_then(|export| export.into_func())
+        .ok_or_else(|| wasmtime::Error::msg("module does not export alloc"))?
+        .typed::<i32, i32>(&caller)?;
+    let ptr = alloc.call(&mut *caller, content.len() as i32)?;
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| wasmtime::Error::msg("module does not export memory"))?;
+    memory.write(&mut *caller, ptr as u32 as usize, content.as_bytes())?;
+    Ok(((ptr as u32 as i64) << 32) | content.len() as i64)
+}
+
+fn host_linker(engine: &Engine) -> wasmtime::Result<Linker<HostState>> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(
+        "intrude",
+        "send",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> wasmtime::Result<()> {
+            let message = read_string(&mut caller, ptr, len)?;
+            caller.data_mut().actions.push(Action::Reply(message));
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "intrude",
+        "call",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> wasmtime::Result<i32> {
+            let req: CallReq = match serde_json::from_str(&read_string(&mut caller, ptr, len)?) {
+                Ok(req) => req,
+                Err(_) => return Ok(-1),
+            };
+            let state = caller.data_mut();
+            if !state.allowed_actions.contains(&req.action) {
+                return Ok(-2);
+            }
+            state.actions.push(Action::Call(req.action, req.params));
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        "intrude",
+        "storage_get",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> wasmtime::Result<i64> {
+            let key = read_string(&mut caller, ptr, len)?;
+            match caller.data().storage.get(&key) {
+                Some(value) => write_string(&mut caller, &value.to_string()),
+                None => Ok(-1),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "intrude",
+        "storage_set",
+        |mut caller: Caller<'_, HostState>,
+         key_ptr: i32,
+         key_len: i32,
+         value_ptr: i32,
+         value_len: i32|
+         -> wasmtime::Result<i32> {
+            let key = read_string(&mut caller, key_ptr, key_len)?;
+            let value = match serde_json::from_str(&read_string(&mut caller, value_ptr, value_len)?)
+            {
+                Ok(value) => value,
+                Err(_) => return Ok(-1),
+            };
+            caller.data().storage.set(&key, value);
+            Ok(0)
+        },
+    )?;
+    Ok(linker)
+}
+
+impl WasmPlugin {
+    pub fn load_dir(config: Option<WasmPluginConfig>) -> Vec<WasmPlugin> {
+        let config = config.unwrap_or_default();
+        let entries = match fs::read_dir(&config.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config).expect("wasm engine creation failed");
+        let linker = Arc::new(host_linker(&engine).expect("wasm host functions failed"));
+        let mut paths: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wasm"))
+            .collect();
+        paths.sort();
+        let mut ret: Vec<WasmPlugin> = Vec::new();
+        for path in paths {
+            match Self::load(&path, &engine, linker.clone(), &config) {
+                Ok(plugin) if ret.iter().any(|other| other.name == plugin.name) => {
+                    warn!(
+                        "wasm module {:?} is ignored, another module is named {}",
+                        path, plugin.name
+                    );
+                }
+                Ok(plugin) => {
+                    info!("loaded wasm plugin {} from {:?}", plugin.name, path);
+                    ret.push(plugin);
+                }
+                Err(err) => warn!("failed to load wasm module {:?}: {}", path, err),
+            }
+        }
+        ret
+    }
+    fn load(
+        path: &Path,
+        engine: &Engine,
+        linker: Arc<Linker<HostState>>,
+        config: &WasmPluginConfig,
+    ) -> BotResult<Self> {
+        // permissions and storage go by the file name, which the module
+        // cannot choose, unlike the name it exports
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| BotError::Wasm("file name is not valid utf-8".to_string()))?;
+        let module = Module::from_file(engine, path)?;
+        // metadata is read with no permissions and throwaway storage
+        let storage = Arc::new(PluginStorage::open(&config.storage_dir, "_"));
+        let (mut store, instance) = Self::instantiate(
+            engine,
+            &module,
+            &linker,
+            config.fuel,
+            config.max_memory,
+            Vec::new(),
+            storage,
+        )?;
+        let mut text = |export: &str| -> BotResult<String> {
+            match instance.get_typed_func::<(), i64>(&mut store, export) {
+                Ok(func) => {
+                    let packed = func.call(&mut store, ())?;
+                    Self::read_packed(&mut store, &instance, packed)
+                }
+                Err(_) => Ok(String::new()),
+            }
+        };
+        let name = text("name")?;
+        if name.is_empty() {
+            return Err(BotError::Wasm("module must export name".to_string()));
+        }
+        let description = text("description")?;
+        let help = text("help")?;
+        let category = match text("category")? {
+            category if category.is_empty() => "other".to_string(),
+            category => category,
+        };
+        let senario = match instance.get_typed_func::<(), i32>(&mut store, "senario") {
+            Ok(func) => match func.call(&mut store, ())? {
+                0 => PluginSenario::Private,
+                1 => PluginSenario::Group,
+                _ => PluginSenario::Both,
+            },
+            Err(_) => PluginSenario::Both,
+        };
+        Ok(WasmPlugin {
+            allowed_actions: config.permissions.get(stem).cloned().unwrap_or_default(),
+            storage: Arc::new(PluginStorage::open(&config.storage_dir, stem)),
+            name: leak(name),
+            description: leak(description),
+            help: leak(help),
+            category: leak(category),
+            senario,
+            engine: engine.clone(),
+            module,
+            linker,
+            fuel: config.fuel,
+            max_memory: config.max_memory,
+        })
+    }
+    fn instantiate(
+        engine: &Engine,
+        module: &Module,
+        linker: &Linker<HostState>,
+        fuel: u64,
+        max_memory: usize,
+        allowed_actions: Vec<String>,
+        storage: Arc<PluginStorage>,
+    ) -> BotResult<(Store<HostState>, Instance)> {
+        let mut store = Store::new(
+            engine,
+            HostState {
+                actions: Vec::new(),
+                allowed_actions,
+                storage,
+                limits: StoreLimitsBuilder::new().memory_size(max_memory).build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(fuel)?;
+        let instance = linker.instantiate(&mut store, module)?;
+        Ok((store, instance))
+    }
+    fn read_packed(
+        store: &mut Store<HostState>,
+        instance: &Instance,
+        packed: i64,
+    ) -> BotResult<String> {
+        let memory = instance
+            .get_memory(&mut *store, "memory")
+            .ok_or_else(|| BotError::Wasm("module does not export memory".to_string()))?;
+        let (ptr, len) = unpack(packed);
+        if ptr
+            .checked_add(len)
+            .is_none_or(|end| end > memory.data_size(&*store))
+        {
+            return Err(BotError::Wasm("string out of bounds".to_string()));
+        }
+        let mut buf = vec![0; len];
+        memory
+            .read(&*store, ptr, &mut buf)
+            .map_err(|err| BotError::Wasm(err.to_string()))?;
+        String::from_utf8(buf).map_err(|err| BotError::Wasm(err.to_string()))
+    }
+    // each event gets a fresh instance, state that should outlive it goes
+    // through storage
+    fn run(&self, event: String) -> BotResult<(PluginFlow, Vec<Action>)> {
+        let (mut store, instance) = Self::instantiate(
+            &self.engine,
+            &self.module,
+            &self.linker,
+            self.fuel,
+            self.max_memory,
+            self.allowed_actions.clone(),
+            self.storage.clone(),
+        )?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let handle = instance.get_typed_func::<(i32, i32), i32>(&mut store, "handle")?;
+        let ptr = alloc.call(&mut store, event.len() as i32)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| BotError::Wasm("module does not export memory".to_string()))?;
+        memory
+            .write(&mut store, ptr as u32 as usize, event.as_bytes())
+            .map_err(|err| BotError::Wasm(err.to_string()))?;
+        let code = handle.call(&mut store, (ptr, event.len() as i32))?;
+        self.storage.flush()?;
+        let flow = match code {
+            0 => PluginFlow::Pass,
+            1 => PluginFlow::Consumed,
+            _ => return Err(BotError::Wasm(format!("handle returned {code}"))),
+        };
+        Ok((flow, std::mem::take(&mut store.data_mut().actions)))
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for WasmPlugin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn description(&self) -> &'static str {
+        self.description
+    }
+    fn help(&self) -> &'static str {
+        self.help
+    }
+    fn senario(&self) -> PluginSenario {
+        self.senario
+    }
+    fn category(&self) -> &'static str {
+        self.category
+    }
+    async fn handle(&self, event: CQEvent, bot: &Bot) -> BotResult<PluginFlow> {
+        let payload = serde_json::to_string(&event)?;
+        let (flow, actions) = tokio::task::block_in_place(|| self.run(payload))?;
+        for action in actions {
+            match action {
+                Action::Reply(message) => {
+                    bot.reply(&event, message).await?;
+                }
+                Action::Call(action, params) => {
+                    bot.api_request::<Value>(&action, params).await?;
+                }
+            }
+        }
+        Ok(flow)
+    }
+}
+
+inventory::submit! {
+    PluginFactory::new("wasm", |config| {
+        Box::pin(async move {
+            Ok(WasmPlugin::load_dir(plugin_config(config)?)
+                .into_iter()
+                .map(|plugin| Box::new(plugin) as BoxedPlugin)
+                .collect())
+        })
+    })
+}
@@ -0,0 +1,201 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use log::warn;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::models::CQEvent;
+
+pub enum PushResult {
+    Queued,
+    // the queue was full and an older event was dropped to make room
+    Shed,
+    // the queue is full of events that must not be dropped
+    Rejected,
+}
+
+#[derive(Serialize)]
+pub struct QueueStats {
+    pub queued: usize,
+    pub capacity: usize,
+    pub shed: u64,
+    pub rejected: u64,
+}
+
+// bounded event queue between the http server and the bot
+pub struct EventQueue {
+    events: Mutex<VecDeque<CQEvent>>,
+    capacity: usize,
+    notify: Notify,
+    shed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize) -> Self {
+        EventQueue {
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity.max(1),
+            notify: Notify::new(),
+            shed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+    // plain chatter may be dropped under load, commands and notices may not
+    fn is_sheddable(event: &CQEvent) -> bool {
+        match event.post_type.as_str() {
+            "message" => !event
+                .raw_message
+                .as_deref()
+                .is_some_and(|msg| msg.starts_with('>')),
+            _ => false,
+        }
+    }
+    pub fn push(&self, event: CQEvent) -> PushResult {
+        let mut events = self.events.lock().unwrap();
+        let mut result = PushResult::Queued;
+        if events.len() >= self.capacity {
+            match events.iter().position(Self::is_sheddable) {
+                Some(index) => {
+                    events.remove(index);
+                    result = PushResult::Shed;
+                }
+                None if Self::is_sheddable(&event) => {
+                    // dropping the newest chatter instead of an older one
+                    drop(events);
+                    self.count_shed();
+                    return PushResult::Shed;
+                }
+                None => {
+                    drop(events);
+                    let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+                    warn!("event queue is saturated, {rejected} event(s) rejected so far");
+                    return PushResult::Rejected;
+                }
+            }
+        }
+        events.push_back(event);
+        drop(events);
+        if let PushResult::Shed = result {
+            self.count_shed();
+        }
+        self.notify.notify_one();
+        result
+    }
+    fn count_shed(&self) {
+        let shed = self.shed.fetch_add(1, Ordering::Relaxed) + 1;
+        // one line per event would flood the log exactly when the bot is slow
+        if shed.is_power_of_two() || shed.is_multiple_of(1000) {
+            warn!("event queue is full, {shed} message(s) shed so far");
+        }
+    }
+    pub async fn pop(&self) -> CQEvent {
+        loop {
+            if let Some(event) = self.events.lock().unwrap().pop_front() {
+                return event;
+            }
+            self.notify.notified().await;
+        }
+    }
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            queued: self.events.lock().unwrap().len(),
+            capacity: self.capacity,
+            shed: self.shed.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(raw_message: &str) -> CQEvent {
+        serde_json::from_value(serde_json::json!({
+            "time": 0,
+            "self_id": 1,
+            "post_type": "message",
+            "message_type": "group",
+            "group_id": 10,
+            "user_id": 2,
+            "raw_message": raw_message,
+        }))
+        .unwrap()
+    }
+
+    fn heartbeat() -> CQEvent {
+        serde_json::from_value(serde_json::json!({
+            "time": 0,
+            "self_id": 1,
+            "post_type": "meta_event",
+            "meta_event_type": "heartbeat",
+            "interval": 5000,
+        }))
+        .unwrap()
+    }
+
+    fn queued(queue: &EventQueue) -> Vec<String> {
+        queue
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| match event.post_type.as_str() {
+                "meta_event" => "heartbeat".to_string(),
+                _ => event.raw_message.clone().unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_queue_sheds_oldest_chatter_for_a_command() {
+        let queue = EventQueue::new(3);
+        queue.push(message(">integral punch"));
+        queue.push(message("hello"));
+        queue.push(message("world"));
+        assert!(matches!(queue.push(message(">help")), PushResult::Shed));
+        assert_eq!(queued(&queue), [">integral punch", "world", ">help"]);
+        assert_eq!(queue.stats().shed, 1);
+    }
+
+    #[test]
+    fn full_queue_keeps_heartbeats() {
+        let queue = EventQueue::new(2);
+        queue.push(heartbeat());
+        queue.push(message(">echo 1"));
+        assert!(matches!(
+            queue.push(message(">echo 2")),
+            PushResult::Rejected
+        ));
+        assert_eq!(queued(&queue), ["heartbeat", ">echo 1"]);
+    }
+
+    #[test]
+    fn full_queue_of_commands_drops_new_chatter() {
+        let queue = EventQueue::new(2);
+        queue.push(message(">echo 1"));
+        queue.push(message(">echo 2"));
+        assert!(matches!(queue.push(message("hello")), PushResult::Shed));
+        assert_eq!(queued(&queue), [">echo 1", ">echo 2"]);
+    }
+
+    #[test]
+    fn full_queue_of_commands_rejects_commands() {
+        let queue = EventQueue::new(2);
+        queue.push(message(">echo 1"));
+        queue.push(message(">echo 2"));
+        assert!(matches!(
+            queue.push(message(">echo 3")),
+            PushResult::Rejected
+        ));
+        assert_eq!(queued(&queue), [">echo 1", ">echo 2"]);
+        assert_eq!(queue.stats().rejected, 1);
+    }
+}
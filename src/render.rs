@@ -0,0 +1,132 @@
+use std::fs;
+
+use fontdue::{Font, FontSettings};
+
+use crate::error::{BotError, BotResult};
+
+const FONT_SIZE: f32 = 28.0;
+const PADDING: usize = 24;
+const ROW_HEIGHT: usize = 48;
+const COLUMN_GAP: usize = 40;
+
+const BACKGROUND: [u8; 3] = [255, 255, 255];
+const STRIPE: [u8; 3] = [242, 244, 247];
+const HEADER: [u8; 3] = [52, 73, 94];
+const TEXT: [u8; 3] = [33, 33, 33];
+const HEADER_TEXT: [u8; 3] = [255, 255, 255];
+
+// draws tables into png images, the font has to cover every character that
+// shows up, CJK included, or they are drawn as boxes
+pub struct TableImage {
+    font: Font,
+}
+
+impl TableImage {
+    pub fn load(path: &str) -> BotResult<Self> {
+        let data = fs::read(path).map_err(|err| BotError::Config(format!("font {path}: {err}")))?;
+        let font = Font::from_bytes(data, FontSettings::default())
+            .map_err(|err| BotError::Config(format!("font {path}: {err}")))?;
+        Ok(TableImage { font })
+    }
+    /// Renders `rows` below a `title` bar, each column as wide as its widest
+    /// cell.
+    pub fn render(&self, title: &str, rows: &[Vec<String>]) -> BotResult<Vec<u8>> {
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let mut widths = vec![0; columns];
+        for row in rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(self.measure(cell));
+            }
+        }
+        let table_width = widths.iter().sum::<usize>() + COLUMN_GAP * columns.saturating_sub(1);
+        let width = 2 * PADDING + table_width.max(self.measure(title));
+        let height = ROW_HEIGHT * (rows.len() + 1) + PADDING;
+        let mut canvas = Canvas::new(width, height);
+        canvas.fill(0, ROW_HEIGHT, HEADER);
+        self.draw_text(&mut canvas, PADDING, 0, title, HEADER_TEXT);
+        for (index, row) in rows.iter().enumerate() {
+            let top = ROW_HEIGHT * (index + 1);
+            if index % 2 == 1 {
+                canvas.fill(top, ROW_HEIGHT, STRIPE);
+            }
+            let mut left = PADDING;
+            for (cell, width) in row.iter().zip(&widths) {
+                self.draw_text(&mut canvas, left, top, cell, TEXT);
+                left += width + COLUMN_GAP;
+            }
+        }
+        canvas.encode()
+    }
+    fn measure(&self, text: &str) -> usize {
+        text.chars()
+            .map(|c| self.font.metrics(c, FONT_SIZE).advance_width)
+            .sum::<f32>()
+            .ceil() as usize
+    }
+    // draws `text` vertically centered in the row starting at `top`
+    fn draw_text(&self, canvas: &mut Canvas, left: usize, top: usize, text: &str, color: [u8; 3]) {
+        let (ascent, descent) = match self.font.horizontal_line_metrics(FONT_SIZE) {
+            Some(metrics) => (metrics.ascent, metrics.descent),
+            None => (FONT_SIZE, 0.0),
+        };
+        let baseline = top as f32 + (ROW_HEIGHT as f32 - (ascent - descent)) / 2.0 + ascent;
+        let mut x = left as f32;
+        for c in text.chars() {
+            let (metrics, coverage) = self.font.rasterize(c, FONT_SIZE);
+            let glyph_left = x.round() as i64 + metrics.xmin as i64;
+            let glyph_top = baseline.round() as i64 - (metrics.height as i64 + metrics.ymin as i64);
+            for (i, alpha) in coverage.iter().enumerate() {
+                let px = glyph_left + (i % metrics.width.max(1)) as i64;
+                let py = glyph_top + (i / metrics.width.max(1)) as i64;
+                canvas.blend(px, py, color, *alpha);
+            }
+            x += metrics.advance_width;
+        }
+    }
+}
+
+struct Canvas {
+    width: usize,
+    height: usize,
+    // rgb, row by row
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Self {
+        Canvas {
+            width,
+            height,
+            pixels: BACKGROUND.repeat(width * height),
+        }
+    }
+    fn fill(&mut self, top: usize, height: usize, color: [u8; 3]) {
+        let rows = top.min(self.height)..(top + height).min(self.height);
+        for pixel in
+            self.pixels[rows.start * self.width * 3..rows.end * self.width * 3].chunks_mut(3)
+        {
+            pixel.copy_from_slice(&color);
+        }
+    }
+    fn blend(&mut self, x: i64, y: i64, color: [u8; 3], alpha: u8) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height || alpha == 0 {
+            return;
+        }
+        let offset = (y as usize * self.width + x as usize) * 3;
+        let alpha = alpha as u32;
+        for (channel, color) in self.pixels[offset..offset + 3].iter_mut().zip(color) {
+            *channel = ((color as u32 * alpha + *channel as u32 * (255 - alpha)) / 255) as u8;
+        }
+    }
+    fn encode(self) -> BotResult<Vec<u8>> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.pixels))
+            .map_err(|err| BotError::Render(err.to_string()))?;
+        Ok(png)
+    }
+}
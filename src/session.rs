@@ -0,0 +1,174 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::sync::{oneshot, Mutex};
+
+use crate::models::CQEvent;
+
+pub struct SessionOptions {
+    pub timeout: Duration,
+    // a message consisting of only this word ends the session
+    pub cancel_word: String,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            timeout: Duration::from_secs(60),
+            cancel_word: "取消".to_string(),
+        }
+    }
+}
+
+pub enum SessionReply {
+    Message(Box<CQEvent>),
+    Cancelled,
+    Timeout,
+}
+
+// (self_id, group_id, user_id), group_id is None for private chats
+type SessionKey = (i64, Option<i64>, i64);
+
+pub struct PendingSession {
+    key: SessionKey,
+    rx: oneshot::Receiver<CQEvent>,
+}
+
+#[derive(Default)]
+pub struct SessionManager {
+    waiting: Mutex<HashMap<SessionKey, oneshot::Sender<CQEvent>>>,
+}
+
+impl SessionManager {
+    fn key(event: &CQEvent) -> Option<SessionKey> {
+        let user_id = event.user_id?;
+        let group_id = match event.message_type.as_deref() {
+            Some("private") => None,
+            _ => event.group_id,
+        };
+        Some((event.self_id, group_id, user_id))
+    }
+    // starts waiting for the next message of the chat, `receive` gets it
+    pub async fn register(&self, event: &CQEvent) -> Option<PendingSession> {
+        let key = Self::key(event)?;
+        let (tx, rx) = oneshot::channel();
+        // a newer session from the same user replaces the old one
+        self.waiting.lock().await.insert(key, tx);
+        Some(PendingSession { key, rx })
+    }
+    pub async fn receive(
+        &self,
+        pending: Option<PendingSession>,
+        options: SessionOptions,
+    ) -> SessionReply {
+        let PendingSession { key, rx } = match pending {
+            Some(pending) => pending,
+            None => return SessionReply::Cancelled,
+        };
+        match tokio::time::timeout(options.timeout, rx).await {
+            Ok(Ok(next)) => {
+                let is_cancel = next
+                    .raw_message
+                    .as_deref()
+                    .is_some_and(|msg| msg.trim() == options.cancel_word);
+                if is_cancel {
+                    SessionReply::Cancelled
+                } else {
+                    SessionReply::Message(Box::new(next))
+                }
+            }
+            Ok(Err(_)) => SessionReply::Cancelled,
+            Err(_) => {
+                self.waiting.lock().await.remove(&key);
+                SessionReply::Timeout
+            }
+        }
+    }
+    // hands the event to a waiting session, giving it back when nobody waits for it
+    pub async fn feed(&self, event: CQEvent) -> Option<CQEvent> {
+        if event.post_type != "message" {
+            return Some(event);
+        }
+        let key = match Self::key(&event) {
+            Some(key) => key,
+            None => return Some(event),
+        };
+        let tx = match self.waiting.lock().await.remove(&key) {
+            Some(tx) => tx,
+            None => return Some(event),
+        };
+        tx.send(event).err()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(group_id: Option<i64>, user_id: i64, raw_message: &str) -> CQEvent {
+        let message_type = match group_id {
+            Some(_) => "group",
+            None => "private",
+        };
+        serde_json::from_value(serde_json::json!({
+            "time": 0,
+            "self_id": 1,
+            "post_type": "message",
+            "message_type": message_type,
+            "group_id": group_id,
+            "user_id": user_id,
+            "raw_message": raw_message,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn feeds_the_next_message_of_the_same_user() {
+        let sessions = SessionManager::default();
+        let pending = sessions.register(&message(Some(10), 2, ">sauce")).await;
+        // other users and chats are not waited for
+        assert!(sessions.feed(message(Some(10), 3, "hi")).await.is_some());
+        assert!(sessions.feed(message(None, 2, "hi")).await.is_some());
+        assert!(sessions.feed(message(Some(10), 2, "image")).await.is_none());
+        let reply = sessions.receive(pending, SessionOptions::default()).await;
+        assert!(matches!(
+            reply,
+            SessionReply::Message(next) if next.raw_message.as_deref() == Some("image")
+        ));
+        // the session ends with the message it got
+        assert!(sessions.feed(message(Some(10), 2, "hi")).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn cancel_word_cancels() {
+        let sessions = SessionManager::default();
+        let pending = sessions.register(&message(None, 2, ">sauce")).await;
+        sessions.feed(message(None, 2, " 取消 ")).await;
+        let reply = sessions.receive(pending, SessionOptions::default()).await;
+        assert!(matches!(reply, SessionReply::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn times_out_and_stops_waiting() {
+        let sessions = SessionManager::default();
+        let pending = sessions.register(&message(Some(10), 2, ">sauce")).await;
+        let options = SessionOptions {
+            timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let reply = sessions.receive(pending, options).await;
+        assert!(matches!(reply, SessionReply::Timeout));
+        assert!(sessions.feed(message(Some(10), 2, "image")).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn newer_session_replaces_the_old_one() {
+        let sessions = SessionManager::default();
+        let old = sessions.register(&message(Some(10), 2, ">sauce")).await;
+        let new = sessions.register(&message(Some(10), 2, ">sauce")).await;
+        let reply = sessions.receive(old, SessionOptions::default()).await;
+        assert!(matches!(reply, SessionReply::Cancelled));
+        sessions.feed(message(Some(10), 2, "image")).await;
+        let reply = sessions.receive(new, SessionOptions::default()).await;
+        assert!(matches!(reply, SessionReply::Message(_)));
+    }
+}
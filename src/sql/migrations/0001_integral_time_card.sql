@@ -0,0 +1,6 @@
+-- the only table from before migrations, databases of that time already have it
+CREATE TABLE IF NOT EXISTS integral_time_card(
+    user_id INTEGER PRIMARY KEY,
+    started_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL
+);
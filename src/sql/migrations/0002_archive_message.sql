@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS archive_message(
+    group_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    time DATETIME NOT NULL,
+    content TEXT NOT NULL,
+    PRIMARY KEY (group_id, message_id)
+);
+CREATE INDEX IF NOT EXISTS archive_message_time ON archive_message(time);
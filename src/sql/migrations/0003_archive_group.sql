@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS archive_group(
+    group_id INTEGER PRIMARY KEY,
+    mode TEXT NOT NULL
+);
@@ -0,0 +1,12 @@
+-- group_id is 0 for private chats, in archive_message as well
+CREATE TABLE IF NOT EXISTS archive_recall(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    group_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    operator_id INTEGER NOT NULL,
+    sent_at DATETIME NOT NULL,
+    recalled_at DATETIME NOT NULL,
+    content TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS archive_recall_group ON archive_recall(group_id, recalled_at);
@@ -0,0 +1,13 @@
+-- time cards used to be kept per user, they are copied into group 0 and
+-- moved to `legacy_group_id` of the integral plugin when it is set
+ALTER TABLE integral_time_card RENAME TO integral_time_card_user;
+CREATE TABLE integral_time_card(
+    group_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    started_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    PRIMARY KEY (group_id, user_id)
+);
+INSERT INTO integral_time_card(group_id, user_id, started_at, updated_at)
+SELECT 0, user_id, started_at, updated_at FROM integral_time_card_user;
+DROP TABLE integral_time_card_user;
@@ -0,0 +1,10 @@
+-- reason is "relapse" or "timeout"
+CREATE TABLE IF NOT EXISTS integral_streak(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    group_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    started_at DATETIME NOT NULL,
+    ended_at DATETIME NOT NULL,
+    reason TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS integral_streak_user ON integral_streak(group_id, user_id);
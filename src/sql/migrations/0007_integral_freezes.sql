@@ -0,0 +1,2 @@
+-- freeze tokens spent on the current streak
+ALTER TABLE integral_time_card ADD COLUMN freezes_used INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS integral_badge(
+    group_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    badge TEXT NOT NULL,
+    earned_at DATETIME NOT NULL,
+    PRIMARY KEY (group_id, user_id, badge)
+);
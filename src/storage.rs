@@ -0,0 +1,52 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde_json::{Map, Value};
+
+use crate::error::{BotError, BotResult};
+
+// key-value storage of one script or wasm plugin, kept in a json file named
+// after the plugin so plugins can't see each other's data
+pub struct PluginStorage {
+    path: PathBuf,
+    data: Mutex<Map<String, Value>>,
+    dirty: Mutex<bool>,
+}
+
+impl PluginStorage {
+    pub fn open(dir: &str, namespace: &str) -> Self {
+        let path = Path::new(dir).join(format!("{namespace}.json"));
+        let data = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        PluginStorage {
+            path,
+            data: Mutex::new(data),
+            dirty: Mutex::new(false),
+        }
+    }
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.data.lock().unwrap().get(key).cloned()
+    }
+    pub fn set(&self, key: &str, value: Value) {
+        self.data.lock().unwrap().insert(key.to_string(), value);
+        *self.dirty.lock().unwrap() = true;
+    }
+    pub fn flush(&self) -> BotResult<()> {
+        let mut dirty = self.dirty.lock().unwrap();
+        if !*dirty {
+            return Ok(());
+        }
+        let content = serde_json::to_string_pretty(&*self.data.lock().unwrap())?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).ok();
+        }
+        fs::write(&self.path, content).map_err(|err| BotError::Config(err.to_string()))?;
+        *dirty = false;
+        Ok(())
+    }
+}